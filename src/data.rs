@@ -1,11 +1,343 @@
-use crate::Value;
-use std::collections::HashMap;
+#[cfg(feature = "parallel")]
+use crate::effects::EffectsCache;
+use crate::{
+    effects::Effects,
+    interner::Interner,
+    memo,
+    memo::EvaluationCache,
+    number,
+    number::Number,
+    ordering, structural,
+    value::{Lock, MaybeSend, MaybeSendSync, Shared},
+    value_kind,
+    value_kind::{Resume, Step},
+    Value,
+};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use once_cell::sync::Lazy;
+use std::{
+    any::TypeId,
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
+pub type IntrinsicFunction = fn(&mut ExecutionContext, Vec<Value>) -> Result<Value, EvaluationError>;
+
+#[derive(Clone, Copy)]
+pub struct Intrinsic {
+    pub function: IntrinsicFunction,
+    pub effects: Effects,
+}
+
+// The intrinsics, global values and attributes are shared with the contexts forked for parallel evaluation, and copied on write.
 pub struct ExecutionContext {
-    #[allow(clippy::type_complexity)]
-    pub intrinsics: HashMap<Value, fn(&mut ExecutionContext, Vec<Value>) -> Value>,
-    pub values: HashMap<Value, Value>,
+    pub intrinsics: Shared<HashMap<Value, Intrinsic>>,
+    pub values: Shared<HashMap<Value, Value>>,
     pub stack: Vec<Value>,
+    // The stack below this length is as it was when the innermost catch was entered. Values popped from below it are recorded
+    // with their positions, so that a catch can put them back without saving the whole stack when it is entered.
+    stack_floor: usize,
+    popped: Vec<(usize, Value)>,
+    pub environment: Option<Shared<Lock<Environment>>>,
+    pub budget: EvaluationBudget,
+    pub interrupted: Arc<AtomicBool>,
+    pub steps: usize,
+    pub depth: usize,
+    pub observers: Vec<Box<dyn EvaluationObserver>>,
+    pub attributes: Shared<HashMap<Value, HashSet<Attribute>>>,
+    pub interner: Option<Interner>,
+    pub cache: Option<EvaluationCache>,
+    #[cfg(feature = "parallel")]
+    pub thread_pool: Option<Arc<rayon::ThreadPool>>,
+    #[cfg(feature = "parallel")]
+    pub(crate) effects_cache: EffectsCache,
+    // The parts of parallel tuples this context is evaluating, innermost last.
+    #[cfg(feature = "parallel")]
+    cancellations: Vec<Cancellation>,
+}
+
+#[derive(Clone, Copy)]
+struct StackScope {
+    length: usize,
+    floor: usize,
+    popped: usize,
+}
+
+// A part of a tuple evaluated in parallel stops once an earlier part has failed, since its result would be discarded anyway.
+#[cfg(feature = "parallel")]
+#[derive(Clone)]
+struct Cancellation {
+    first_failed: Arc<AtomicUsize>,
+    part: usize,
+}
+
+impl ExecutionContext {
+    pub fn new(intrinsics: HashMap<Value, Intrinsic>) -> Self {
+        ExecutionContext {
+            intrinsics: Shared::new(intrinsics),
+            values: Shared::new(HashMap::new()),
+            stack: Vec::new(),
+            stack_floor: 0,
+            popped: Vec::new(),
+            environment: None,
+            budget: EvaluationBudget::default(),
+            interrupted: Arc::new(AtomicBool::new(false)),
+            steps: 0,
+            depth: 0,
+            observers: Vec::new(),
+            attributes: Shared::new(HashMap::new()),
+            interner: None,
+            cache: None,
+            #[cfg(feature = "parallel")]
+            thread_pool: None,
+            #[cfg(feature = "parallel")]
+            effects_cache: EffectsCache::default(),
+            #[cfg(feature = "parallel")]
+            cancellations: Vec::new(),
+        }
+    }
+
+    // Pure subterms of tuples are evaluated on a pool of the given number of threads, or sequentially if it is at most one.
+    #[cfg(feature = "parallel")]
+    pub fn set_threads(&mut self, threads: usize) {
+        self.thread_pool = if threads > 1 {
+            Some(Arc::new(rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap()))
+        } else {
+            None
+        };
+    }
+
+    // A context for evaluating a pure subterm on another thread. It starts with no steps taken and the remaining step budget, so
+    // that its steps can be added back to this context afterwards.
+    #[cfg(feature = "parallel")]
+    pub fn fork(&self) -> Self {
+        ExecutionContext {
+            intrinsics: self.intrinsics.clone(),
+            values: self.values.clone(),
+            stack: Vec::new(),
+            stack_floor: 0,
+            popped: Vec::new(),
+            environment: self.environment.clone(),
+            budget: EvaluationBudget {
+                max_steps: self.budget.max_steps.map(|max_steps| max_steps.saturating_sub(self.steps)),
+                ..self.budget
+            },
+            interrupted: self.interrupted.clone(),
+            steps: 0,
+            depth: self.depth,
+            observers: Vec::new(),
+            attributes: self.attributes.clone(),
+            interner: None,
+            cache: None,
+            thread_pool: self.thread_pool.clone(),
+            effects_cache: EffectsCache::default(),
+            cancellations: self.cancellations.clone(),
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    fn is_cancelled(&self) -> bool {
+        self.cancellations
+            .iter()
+            .any(|cancellation| cancellation.first_failed.load(Ordering::SeqCst) < cancellation.part)
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+
+    pub fn notify<F: FnMut(&mut dyn EvaluationObserver, &ExecutionContext)>(&mut self, mut f: F) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let mut observers = std::mem::take(&mut self.observers);
+        for observer in &mut observers {
+            f(observer.as_mut(), self);
+        }
+        self.observers = observers;
+    }
+
+    pub fn push(&mut self, value: Value) {
+        self.stack.push(value.clone());
+        self.notify(|observer, execution_context| observer.on_push(execution_context, &value));
+    }
+
+    pub fn pop(&mut self) -> Option<Value> {
+        let value = self.stack.pop()?;
+        if self.stack.len() < self.stack_floor {
+            self.stack_floor = self.stack.len();
+            self.popped.push((self.stack.len(), value.clone()));
+        }
+        self.notify(|observer, execution_context| observer.on_pop(execution_context, &value));
+        Some(value)
+    }
+
+    pub fn check_budget(&mut self, value: &Value) -> Result<(), EvaluationError> {
+        self.steps += 1;
+        let reason = if self.interrupted.swap(false, Ordering::SeqCst) || self.is_cancelled() {
+            EvaluationErrorReason::Interrupted
+        } else if matches!(self.budget.max_steps, Some(max_steps) if self.steps > max_steps) {
+            EvaluationErrorReason::StepLimitExceeded
+        } else if matches!(self.budget.max_depth, Some(max_depth) if self.depth > max_depth) {
+            EvaluationErrorReason::DepthLimitExceeded
+        } else if matches!(self.budget.deadline, Some(deadline) if Instant::now() >= deadline) {
+            EvaluationErrorReason::DeadlineExceeded
+        } else {
+            return Ok(());
+        };
+        Err(EvaluationError::new(value.clone(), reason))
+    }
+
+    pub fn lookup(&self, symbol: &Value) -> Option<Value> {
+        let mut environment = self.environment.clone();
+        while let Some(current) = environment {
+            let current = current.borrow();
+            if let Some(value) = current.values.get(symbol) {
+                return Some(value.clone());
+            }
+            environment = current.parent.clone();
+        }
+        self.values.get(symbol).cloned()
+    }
+
+    pub fn assign(&mut self, symbol: Value, value: Value) {
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate(&symbol);
+        }
+        #[cfg(feature = "parallel")]
+        self.effects_cache.clear();
+        let mut environment = self.environment.clone();
+        while let Some(current) = environment {
+            let mut current = current.borrow_mut();
+            if let Some(slot) = current.values.get_mut(&symbol) {
+                *slot = value;
+                return;
+            }
+            environment = current.parent.clone();
+        }
+        Shared::make_mut(&mut self.values).insert(symbol, value);
+    }
+
+    fn stack_scope(&self) -> StackScope {
+        StackScope {
+            length: self.stack.len(),
+            floor: self.stack_floor,
+            popped: self.popped.len(),
+        }
+    }
+
+    // Starts recording the values popped from the stack as it is now.
+    fn enter_stack_scope(&mut self) -> StackScope {
+        let scope = self.stack_scope();
+        self.stack_floor = self.stack.len();
+        scope
+    }
+
+    // Keeps only the values recorded since entering the scope that the enclosing scopes need.
+    fn leave_stack_scope(&mut self, scope: StackScope) {
+        let start = scope.popped.min(self.popped.len());
+        let needed = self.popped.split_off(start).into_iter().filter(|(position, _)| *position < scope.floor);
+        self.popped.extend(needed);
+        self.stack_floor = self.stack_floor.min(scope.floor);
+    }
+
+    // Pops the values pushed since entering the scope and pushes back the ones popped since then.
+    fn restore_stack_scope(&mut self, scope: StackScope) {
+        let popped = self.popped.split_off(scope.popped.min(self.popped.len()));
+        let kept = popped
+            .iter()
+            .map(|(position, _)| *position)
+            .fold(scope.length.min(self.stack.len()), usize::min);
+        // The values above the untouched part were pushed since entering, so they are not recorded.
+        self.stack_floor = 0;
+        while self.stack.len() > kept {
+            self.pop();
+        }
+        for position in kept..scope.length {
+            // The first value popped from a position is the one it held when the scope was entered.
+            if let Some((_, value)) = popped.iter().find(|(popped_position, _)| *popped_position == position) {
+                self.push(value.clone());
+            }
+        }
+        self.stack_floor = scope.floor;
+    }
+
+    pub fn has_attribute(&self, head: &Value, attribute: Attribute) -> bool {
+        match head_symbol(head) {
+            Some(symbol) => matches!(self.attributes.get(&symbol), Some(attributes) if attributes.contains(&attribute)),
+            None => false,
+        }
+    }
+}
+
+#[allow(unused_variables)]
+pub trait EvaluationObserver: MaybeSend {
+    fn on_step_start(&mut self, execution_context: &ExecutionContext, value: &Value) {}
+
+    fn on_step(&mut self, execution_context: &ExecutionContext, input: &Value, output: &Value) {}
+
+    fn on_dereference(&mut self, execution_context: &ExecutionContext, symbol: &Value, value: &Value) {}
+
+    fn on_intrinsic_call_start(&mut self, execution_context: &ExecutionContext, intrinsic: &Value, arguments: &[Value]) {}
+
+    fn on_intrinsic_call(&mut self, execution_context: &ExecutionContext, intrinsic: &Value, arguments: &[Value], result: &Value) {}
+
+    fn on_assignment(&mut self, execution_context: &ExecutionContext, target: &Value, value: &Value) {}
+
+    fn on_push(&mut self, execution_context: &ExecutionContext, value: &Value) {}
+
+    fn on_pop(&mut self, execution_context: &ExecutionContext, value: &Value) {}
+}
+
+// With the sync feature an execution context can be moved to another thread, along with everything it holds.
+#[cfg(feature = "sync")]
+#[allow(dead_code)]
+fn assert_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<ExecutionContext>();
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct EvaluationBudget {
+    pub max_steps: Option<usize>,
+    pub max_depth: Option<usize>,
+    pub deadline: Option<Instant>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Attribute {
+    HoldAll,
+    HoldFirst,
+    HoldRest,
+    Listable,
+    Flat,
+    Orderless,
+}
+
+impl Attribute {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "HoldAll" => Some(Attribute::HoldAll),
+            "HoldFirst" => Some(Attribute::HoldFirst),
+            "HoldRest" => Some(Attribute::HoldRest),
+            "Listable" => Some(Attribute::Listable),
+            "Flat" => Some(Attribute::Flat),
+            "Orderless" => Some(Attribute::Orderless),
+            _ => None,
+        }
+    }
+}
+
+pub struct Environment {
+    pub values: HashMap<Value, Value>,
+    pub parent: Option<Shared<Lock<Environment>>>,
 }
 
 pub struct HoldValueInner {
@@ -34,6 +366,18 @@ pub struct ExecutableFunctionValueInner {
     pub body: Value,
 }
 
+pub struct ClosureValueInner {
+    pub arguments: Value,
+    pub body: Value,
+    pub environment: Option<Shared<Lock<Environment>>>,
+}
+
+pub struct IfValueInner {
+    pub condition: Value,
+    pub consequent: Value,
+    pub alternative: Value,
+}
+
 pub struct FunctionApplicationValueInner {
     pub function: Value,
     pub arguments: Value,
@@ -44,169 +388,2459 @@ pub struct IntrinsicCallValueInner {
     pub arguments: Value,
 }
 
+// The tag is not evaluated, a `Null` tag marks an untagged throw.
+pub struct ThrowValueInner {
+    pub tag: Value,
+    pub inner: Value,
+}
+
+// Catches throws with the given tag, or all throws if the tag is `Null`, by applying the handler to the thrown value.
+pub struct CatchValueInner {
+    pub tag: Value,
+    pub inner: Value,
+    pub handler: Value,
+}
+
+pub struct FinallyValueInner {
+    pub inner: Value,
+    pub cleanup: Value,
+}
+
+// Evaluates the body for as long as the condition evaluates to true.
+pub struct WhileValueInner {
+    pub condition: Value,
+    pub body: Value,
+}
+
+// Evaluates the body with the variable bound to each number from `start` to `end` inclusive, `step` apart.
+pub struct ForValueInner {
+    pub variable: Value,
+    pub start: Value,
+    pub end: Value,
+    pub step: Value,
+    pub body: Value,
+}
+
+// Evaluates the body with the variable bound to each element of the tuple that `elements` evaluates to.
+pub struct DoValueInner {
+    pub variable: Value,
+    pub elements: Value,
+    pub body: Value,
+}
+
+pub struct BreakValueInner;
+
+pub struct ContinueValueInner;
+
+pub struct ResetValueInner {
+    pub inner: Value,
+}
+
+// Applies the handler to the continuation of the shift up to the nearest enclosing reset, whose evaluation continues with the
+// handler instead.
+pub struct ShiftValueInner {
+    pub handler: Value,
+}
+
+// A captured part of the evaluation, which continues with its argument as the value of the shift it was captured by.
+pub struct ContinuationValueInner {
+    frames: Vec<Frame>,
+    environment: Option<Shared<Lock<Environment>>>,
+    depth: usize,
+}
+
 pub struct TupleValueInner {
     pub inner: Vec<Value>,
 }
 
+pub struct AssociationValueInner {
+    pub inner: Vec<(Value, Value)>,
+}
+
+impl AssociationValueInner {
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.inner
+            .iter()
+            .find(|(entry_key, _)| equal(entry_key, key))
+            .map(|(_, entry_value)| entry_value)
+    }
+
+    pub fn insert(&mut self, key: Value, value: Value) {
+        match self.inner.iter_mut().find(|(entry_key, _)| equal(entry_key, &key)) {
+            Some((_, entry_value)) => *entry_value = value,
+            None => self.inner.push((key, value)),
+        }
+    }
+
+    pub fn remove(&mut self, key: &Value) -> Option<Value> {
+        let index = self.inner.iter().position(|(entry_key, _)| equal(entry_key, key))?;
+        Some(self.inner.remove(index).1)
+    }
+
+    pub fn parts(&self) -> Vec<Value> {
+        self.inner.iter().flat_map(|(key, value)| vec![key.clone(), value.clone()]).collect()
+    }
+}
+
+pub struct BlankValueInner {
+    pub kind: Option<String>,
+}
+
+pub struct PatternValueInner {
+    pub name: Value,
+    pub inner: Value,
+}
+
+pub struct RepeatedPatternValueInner {
+    pub inner: Value,
+    pub minimum: usize,
+}
+
+pub struct RuleValueInner {
+    pub left: Value,
+    pub right: Value,
+}
+
 pub struct NullValueInner;
 
 pub struct SymbolValueInner {
     pub name: String,
+    // Fresh symbols are generated by capture-avoiding substitution. They are identified by the node rather than by the name,
+    // so that they cannot collide with a symbol of the same name written elsewhere.
+    pub fresh: bool,
+}
+
+pub struct StringValueInner {
+    pub inner: String,
+}
+
+pub struct BooleanValueInner {
+    pub inner: bool,
+}
+
+pub struct IntegerValueInner {
+    pub inner: BigInt,
+}
+
+pub struct RationalValueInner {
+    pub inner: BigRational,
 }
 
 pub struct FloatingPointNumberValueInner {
     pub inner: f64,
 }
 
-pub fn evaluate_once(execution_context: &mut ExecutionContext, value: Value) -> Value {
-    if let Some(value_inner) = value.try_downcast::<ReleaseValueInner>() {
-        let inner = evaluate(execution_context, value_inner.inner.clone());
-        inner.downcast::<HoldValueInner>().inner.clone()
-    } else if let Some(value_inner) = value.try_downcast::<AssignmentValueInner>() {
-        let source = evaluate(execution_context, value_inner.source.clone());
-        let target = evaluate(execution_context, value_inner.target.clone());
-        assert!(target.is::<SymbolValueInner>());
-        execution_context.values.insert(target, source);
-        Value::new(NullValueInner)
-    } else if let Some(value_inner) = value.try_downcast::<DereferenceValueInner>() {
-        let inner = evaluate(execution_context, value_inner.inner.clone());
-        assert!(inner.is::<SymbolValueInner>());
-        execution_context.values.get(&inner).cloned().unwrap_or(inner)
-    } else if let Some(value_inner) = value.try_downcast::<ExecutableSequenceValueInner>() {
-        value_inner
-            .inner
-            .iter()
-            .map(|x| evaluate(execution_context, x.clone()))
-            .last()
-            .unwrap_or_else(|| Value::new(NullValueInner))
-    } else if let Some(value_inner) = value.try_downcast::<ExecutableFunctionValueInner>() {
-        let arguments = evaluate(execution_context, value_inner.arguments.clone());
-        let body = evaluate(execution_context, value_inner.body.clone());
-        if arguments == value_inner.arguments && body == value_inner.body {
-            value
-        } else {
-            Value::new(ExecutableFunctionValueInner { arguments, body })
-        }
-    } else if let Some(value_inner) = value.try_downcast::<FunctionApplicationValueInner>() {
-        let function = evaluate(execution_context, value_inner.function.clone());
-        let arguments = evaluate(execution_context, value_inner.arguments.clone());
-        #[allow(clippy::collapsible_if)]
-        if let Some(function) = function.try_downcast::<ExecutableFunctionValueInner>() {
-            let arguments = arguments.downcast::<TupleValueInner>().inner.clone();
-            let function_arguments = function.arguments.downcast::<TupleValueInner>().inner.clone();
-            assert_eq!(function_arguments.len(), arguments.len());
-            let mut result = function.body.clone();
-            for (from, to) in function_arguments.iter().cloned().zip(arguments) {
-                assert!(from.is::<SymbolValueInner>());
-                result = replace(result, from, to);
-            }
-            assert!(result.is::<HoldValueInner>());
-            evaluate(execution_context, Value::new(ReleaseValueInner { inner: result }))
-        } else {
-            if function == value_inner.function && arguments == value_inner.arguments {
-                value
+#[derive(Debug)]
+pub enum EvaluationErrorReason {
+    UnexpectedKind { expected: &'static str },
+    ArgumentCountMismatch { expected: usize, actual: usize },
+    UnknownIntrinsic,
+    UnknownKind,
+    EmptyStack,
+    DivisionByZero,
+    IndexOutOfBounds,
+    KeyNotFound,
+    InvalidNumber,
+    UnknownAttribute,
+    LengthMismatch,
+    UncaughtThrow { tag: Value },
+    NoEnclosingReset,
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+    StepLimitExceeded,
+    DepthLimitExceeded,
+    DeadlineExceeded,
+    Interrupted,
+}
+
+impl EvaluationErrorReason {
+    pub fn is_limit(&self) -> bool {
+        matches!(
+            self,
+            EvaluationErrorReason::StepLimitExceeded
+                | EvaluationErrorReason::DepthLimitExceeded
+                | EvaluationErrorReason::DeadlineExceeded
+                | EvaluationErrorReason::Interrupted
+        )
+    }
+}
+
+impl fmt::Display for EvaluationErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvaluationErrorReason::UnexpectedKind { expected } => write!(f, "expected a value of kind {}", expected),
+            EvaluationErrorReason::ArgumentCountMismatch { expected, actual } => write!(f, "expected {} arguments, got {}", expected, actual),
+            EvaluationErrorReason::UnknownIntrinsic => write!(f, "unknown intrinsic"),
+            EvaluationErrorReason::UnknownKind => write!(f, "the kind of the value is not registered"),
+            EvaluationErrorReason::EmptyStack => write!(f, "the stack is empty"),
+            EvaluationErrorReason::DivisionByZero => write!(f, "division by zero"),
+            EvaluationErrorReason::IndexOutOfBounds => write!(f, "index out of bounds"),
+            EvaluationErrorReason::KeyNotFound => write!(f, "key not found"),
+            EvaluationErrorReason::InvalidNumber => write!(f, "invalid number"),
+            EvaluationErrorReason::UnknownAttribute => write!(f, "unknown attribute"),
+            EvaluationErrorReason::LengthMismatch => write!(f, "the tuples have different lengths"),
+            EvaluationErrorReason::UncaughtThrow { .. } => write!(f, "a thrown value was not caught"),
+            EvaluationErrorReason::NoEnclosingReset => write!(f, "shift outside of a reset"),
+            EvaluationErrorReason::BreakOutsideLoop => write!(f, "break outside of a loop"),
+            EvaluationErrorReason::ContinueOutsideLoop => write!(f, "continue outside of a loop"),
+            EvaluationErrorReason::StepLimitExceeded => write!(f, "the step limit was exceeded"),
+            EvaluationErrorReason::DepthLimitExceeded => write!(f, "the recursion depth limit was exceeded"),
+            EvaluationErrorReason::DeadlineExceeded => write!(f, "the deadline was exceeded"),
+            EvaluationErrorReason::Interrupted => write!(f, "the evaluation was interrupted"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EvaluationError {
+    pub value: Value,
+    pub reason: EvaluationErrorReason,
+}
+
+impl EvaluationError {
+    pub fn new(value: Value, reason: EvaluationErrorReason) -> Self {
+        EvaluationError { value, reason }
+    }
+}
+
+impl fmt::Display for EvaluationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for EvaluationError {}
+
+pub fn expect_kind<T: MaybeSendSync + 'static>(value: &Value, expected: &'static str) -> Result<Shared<T>, EvaluationError> {
+    value
+        .try_downcast::<T>()
+        .ok_or_else(|| EvaluationError::new(value.clone(), EvaluationErrorReason::UnexpectedKind { expected }))
+}
+
+#[derive(Clone)]
+enum Iteration {
+    WhileCondition(Shared<WhileValueInner>),
+    WhileBody(Shared<WhileValueInner>),
+    For {
+        value_inner: Shared<ForValueInner>,
+        next: Number,
+        end: Number,
+        step: Number,
+        ascending: bool,
+    },
+    Do {
+        value_inner: Shared<DoValueInner>,
+        elements: Vec<Value>,
+        index: usize,
+    },
+}
+
+#[derive(Clone)]
+enum Frame {
+    Fixpoint {
+        value: Value,
+    },
+    Release,
+    AssignmentSource {
+        value_inner: Shared<AssignmentValueInner>,
+    },
+    AssignmentTarget {
+        source: Value,
+    },
+    Dereference,
+    ExecutableSequence {
+        value_inner: Shared<ExecutableSequenceValueInner>,
+        index: usize,
+    },
+    ExecutableFunctionArguments {
+        value_inner: Shared<ExecutableFunctionValueInner>,
+    },
+    ExecutableFunctionBody {
+        arguments: Value,
+    },
+    If {
+        value_inner: Shared<IfValueInner>,
+    },
+    FunctionApplicationFunction {
+        value: Value,
+        value_inner: Shared<FunctionApplicationValueInner>,
+    },
+    FunctionApplicationArguments {
+        value: Value,
+        value_inner: Shared<FunctionApplicationValueInner>,
+        function: Value,
+        held: Vec<bool>,
+    },
+    // The functions and arguments whose applications all return the result passing through, which is stored for each of them.
+    // Consecutive memoized applications share a frame, so that chains of tail calls do not grow the frames.
+    Memoize {
+        applications: Vec<(Value, Value)>,
+    },
+    RestoreEnvironment {
+        environment: Option<Shared<Lock<Environment>>>,
+    },
+    Throw {
+        tag: Value,
+    },
+    Catch {
+        value_inner: Shared<CatchValueInner>,
+        environment: Option<Shared<Lock<Environment>>>,
+        depth: usize,
+        stack: StackScope,
+    },
+    Finally {
+        value_inner: Shared<FinallyValueInner>,
+        environment: Option<Shared<Lock<Environment>>>,
+        depth: usize,
+    },
+    FinallyReturn {
+        result: Value,
+        settled: bool,
+    },
+    FinallyResume {
+        exit: Exit,
+    },
+    ForRange {
+        value_inner: Shared<ForValueInner>,
+    },
+    DoElements {
+        value_inner: Shared<DoValueInner>,
+    },
+    Loop {
+        iteration: Iteration,
+        environment: Option<Shared<Lock<Environment>>>,
+        depth: usize,
+    },
+    Resume {
+        resume: Shared<dyn Resume>,
+    },
+    Reset {
+        environment: Option<Shared<Lock<Environment>>>,
+        depth: usize,
+    },
+    IntrinsicCallIntrinsic {
+        value_inner: Shared<IntrinsicCallValueInner>,
+    },
+    IntrinsicCallArguments {
+        value_inner: Shared<IntrinsicCallValueInner>,
+        intrinsic: Value,
+        held: Vec<bool>,
+    },
+    Tuple {
+        value: Value,
+        value_inner: Shared<TupleValueInner>,
+        inner: Vec<Value>,
+    },
+    Association {
+        value: Value,
+        value_inner: Shared<AssociationValueInner>,
+        inner: Vec<Value>,
+    },
+}
+
+fn head_symbol(head: &Value) -> Option<Value> {
+    if head.is::<SymbolValueInner>() {
+        Some(head.clone())
+    } else if let Some(head_inner) = head.try_downcast::<DereferenceValueInner>() {
+        Some(head_inner.inner.clone()).filter(|inner| inner.is::<SymbolValueInner>())
+    } else {
+        None
+    }
+}
+
+fn application_parts(value: &Value) -> Option<(Value, Value)> {
+    if let Some(value_inner) = value.try_downcast::<FunctionApplicationValueInner>() {
+        Some((value_inner.function.clone(), value_inner.arguments.clone()))
+    } else {
+        value
+            .try_downcast::<IntrinsicCallValueInner>()
+            .map(|value_inner| (value_inner.intrinsic.clone(), value_inner.arguments.clone()))
+    }
+}
+
+fn is_held(execution_context: &ExecutionContext, head: &Value, index: usize) -> bool {
+    execution_context.has_attribute(head, Attribute::HoldAll)
+        || index == 0 && execution_context.has_attribute(head, Attribute::HoldFirst)
+        || index > 0 && execution_context.has_attribute(head, Attribute::HoldRest)
+}
+
+// Held arguments are wrapped in a `Hold` so that evaluating the argument tuple leaves them untouched, and unwrapped again
+// by `release_arguments` once the tuple has been evaluated. The indices held are returned, since evaluating the arguments may
+// change the attributes of the head.
+fn hold_arguments(execution_context: &ExecutionContext, head: &Value, arguments: Value) -> (Value, Vec<bool>) {
+    match arguments.try_downcast::<TupleValueInner>() {
+        Some(arguments_inner) if (0..arguments_inner.inner.len()).any(|index| is_held(execution_context, head, index)) => {
+            let held = (0..arguments_inner.inner.len())
+                .map(|index| is_held(execution_context, head, index))
+                .collect::<Vec<_>>();
+            let arguments = Value::new(TupleValueInner {
+                inner: arguments_inner
+                    .inner
+                    .iter()
+                    .zip(&held)
+                    .map(|(argument, held)| {
+                        if *held {
+                            Value::new(HoldValueInner { inner: argument.clone() })
+                        } else {
+                            argument.clone()
+                        }
+                    })
+                    .collect(),
+            });
+            (arguments, held)
+        }
+        _ => (arguments, Vec::new()),
+    }
+}
+
+fn release_arguments(held: &[bool], original: &Value, arguments: Value) -> Result<Value, EvaluationError> {
+    if held.is_empty() {
+        return Ok(arguments);
+    }
+    let original_inner = expect_kind::<TupleValueInner>(original, "Tuple")?;
+    let arguments_inner = expect_kind::<TupleValueInner>(&arguments, "Tuple")?;
+    let inner = arguments_inner
+        .inner
+        .iter()
+        .zip(held)
+        .map(|(argument, held)| {
+            Ok(if *held {
+                expect_kind::<HoldValueInner>(argument, "Hold")?.inner.clone()
             } else {
-                Value::new(FunctionApplicationValueInner { function, arguments })
+                argument.clone()
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(if inner == original_inner.inner {
+        original.clone()
+    } else {
+        Value::new(TupleValueInner { inner })
+    })
+}
+
+fn normalize_arguments(execution_context: &ExecutionContext, head: &Value, arguments: Value) -> Value {
+    let flat = execution_context.has_attribute(head, Attribute::Flat);
+    let orderless = execution_context.has_attribute(head, Attribute::Orderless);
+    let arguments_inner = match arguments.try_downcast::<TupleValueInner>() {
+        Some(arguments_inner) if flat || orderless => arguments_inner,
+        _ => return arguments,
+    };
+    let mut inner = Vec::new();
+    for argument in &arguments_inner.inner {
+        match application_parts(argument) {
+            Some((argument_head, argument_arguments)) if flat && head_symbol(&argument_head) == head_symbol(head) => {
+                match argument_arguments.try_downcast::<TupleValueInner>() {
+                    Some(argument_arguments) => inner.extend(argument_arguments.inner.iter().cloned()),
+                    None => inner.push(argument.clone()),
+                }
+            }
+            _ => inner.push(argument.clone()),
+        }
+    }
+    if orderless {
+        inner.sort_by(ordering::compare);
+    }
+    if inner == arguments_inner.inner {
+        arguments
+    } else {
+        Value::new(TupleValueInner { inner })
+    }
+}
+
+// Threads a `Listable` head over the tuples among its arguments, so that `f((a, b), c)` becomes `(f(a, c), f(b, c))`.
+fn thread_arguments<F: Fn(Value) -> Value>(
+    execution_context: &ExecutionContext,
+    head: &Value,
+    arguments: &Value,
+    make: F,
+) -> Result<Option<Value>, EvaluationError> {
+    if !execution_context.has_attribute(head, Attribute::Listable) {
+        return Ok(None);
+    }
+    let arguments_inner = match arguments.try_downcast::<TupleValueInner>() {
+        Some(arguments_inner) => arguments_inner,
+        None => return Ok(None),
+    };
+    let mut length = None;
+    for argument in &arguments_inner.inner {
+        if let Some(argument_inner) = argument.try_downcast::<TupleValueInner>() {
+            match length {
+                Some(length) if length != argument_inner.inner.len() => {
+                    return Err(EvaluationError::new(arguments.clone(), EvaluationErrorReason::LengthMismatch));
+                }
+                _ => length = Some(argument_inner.inner.len()),
+            }
+        }
+    }
+    let length = match length {
+        Some(length) => length,
+        None => return Ok(None),
+    };
+    Ok(Some(Value::new(TupleValueInner {
+        inner: (0..length)
+            .map(|index| {
+                make(Value::new(TupleValueInner {
+                    inner: arguments_inner
+                        .inner
+                        .iter()
+                        .map(|argument| match argument.try_downcast::<TupleValueInner>() {
+                            Some(argument_inner) => argument_inner.inner[index].clone(),
+                            None => argument.clone(),
+                        })
+                        .collect(),
+                }))
+            })
+            .collect(),
+    })))
+}
+
+#[derive(Clone)]
+enum Exit {
+    Throw { tag: Value, value: Value },
+    Break,
+    Continue,
+}
+
+enum State {
+    Evaluate(Value),
+    EvaluateOnce(Value),
+    Return(Value),
+    // Like `Return`, but the value is known to be a fixpoint of `evaluate`, so evaluating it once more can be skipped.
+    ReturnSettled(Value),
+    Unwind(Exit),
+}
+
+type StepFunction = fn(&mut ExecutionContext, &mut Vec<Frame>, Value) -> Result<State, EvaluationError>;
+
+fn step_release(_execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    frames.push(Frame::Release);
+    Ok(State::Evaluate(value.downcast::<ReleaseValueInner>().inner.clone()))
+}
+
+fn step_assignment(_execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<AssignmentValueInner>();
+    let source = value_inner.source.clone();
+    frames.push(Frame::AssignmentSource { value_inner });
+    Ok(State::Evaluate(source))
+}
+
+fn step_dereference(_execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    frames.push(Frame::Dereference);
+    Ok(State::Evaluate(value.downcast::<DereferenceValueInner>().inner.clone()))
+}
+
+fn step_executable_sequence(_execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<ExecutableSequenceValueInner>();
+    Ok(match value_inner.inner.first() {
+        Some(first) => {
+            let first = first.clone();
+            if value_inner.inner.len() > 1 {
+                frames.push(Frame::ExecutableSequence { value_inner, index: 1 });
             }
+            State::Evaluate(first)
         }
-    } else if let Some(value_inner) = value.try_downcast::<IntrinsicCallValueInner>() {
-        let intrinsic = evaluate(execution_context, value_inner.intrinsic.clone());
-        let arguments = evaluate(execution_context, value_inner.arguments.clone());
-        let arguments = arguments.downcast::<TupleValueInner>().inner.clone();
-        (execution_context.intrinsics.get(&intrinsic).unwrap())(execution_context, arguments)
-    } else if let Some(value_inner) = value.try_downcast::<TupleValueInner>() {
-        let inner = value_inner.inner.iter().map(|x| evaluate(execution_context, x.clone())).collect();
+        None => State::ReturnSettled(Value::new(NullValueInner)),
+    })
+}
+
+fn step_executable_function(_execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<ExecutableFunctionValueInner>();
+    let arguments = value_inner.arguments.clone();
+    frames.push(Frame::ExecutableFunctionArguments { value_inner });
+    Ok(State::Evaluate(arguments))
+}
+
+fn step_if(_execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<IfValueInner>();
+    let condition = value_inner.condition.clone();
+    frames.push(Frame::If { value_inner });
+    Ok(State::Evaluate(condition))
+}
+
+fn step_function_application(_execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<FunctionApplicationValueInner>();
+    let function = value_inner.function.clone();
+    frames.push(Frame::FunctionApplicationFunction { value, value_inner });
+    Ok(State::Evaluate(function))
+}
+
+fn step_intrinsic_call(_execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<IntrinsicCallValueInner>();
+    let intrinsic = value_inner.intrinsic.clone();
+    frames.push(Frame::IntrinsicCallIntrinsic { value_inner });
+    Ok(State::Evaluate(intrinsic))
+}
+
+fn step_throw(_execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<ThrowValueInner>();
+    frames.push(Frame::Throw { tag: value_inner.tag.clone() });
+    Ok(State::Evaluate(value_inner.inner.clone()))
+}
+
+fn step_catch(execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<CatchValueInner>();
+    let inner = value_inner.inner.clone();
+    frames.push(Frame::Catch {
+        value_inner,
+        environment: execution_context.environment.clone(),
+        depth: execution_context.depth,
+        stack: execution_context.enter_stack_scope(),
+    });
+    Ok(State::Evaluate(inner))
+}
+
+fn step_finally(execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<FinallyValueInner>();
+    let inner = value_inner.inner.clone();
+    frames.push(Frame::Finally {
+        value_inner,
+        environment: execution_context.environment.clone(),
+        depth: execution_context.depth,
+    });
+    Ok(State::Evaluate(inner))
+}
+
+fn step_while(execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let environment = execution_context.environment.clone();
+    let depth = execution_context.depth;
+    advance(
+        execution_context,
+        frames,
+        Iteration::WhileCondition(value.downcast::<WhileValueInner>()),
+        environment,
+        depth,
+    )
+}
+
+fn step_for(_execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<ForValueInner>();
+    expect_kind::<SymbolValueInner>(&value_inner.variable, "Symbol")?;
+    let range = Value::new(TupleValueInner {
+        inner: vec![value_inner.start.clone(), value_inner.end.clone(), value_inner.step.clone()],
+    });
+    frames.push(Frame::ForRange { value_inner });
+    Ok(State::Evaluate(range))
+}
+
+fn step_do(_execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<DoValueInner>();
+    expect_kind::<SymbolValueInner>(&value_inner.variable, "Symbol")?;
+    let elements = value_inner.elements.clone();
+    frames.push(Frame::DoElements { value_inner });
+    Ok(State::Evaluate(elements))
+}
+
+fn step_break(_execution_context: &mut ExecutionContext, _frames: &mut Vec<Frame>, _value: Value) -> Result<State, EvaluationError> {
+    Ok(State::Unwind(Exit::Break))
+}
+
+fn step_continue(_execution_context: &mut ExecutionContext, _frames: &mut Vec<Frame>, _value: Value) -> Result<State, EvaluationError> {
+    Ok(State::Unwind(Exit::Continue))
+}
+
+fn step_reset(execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    frames.push(Frame::Reset {
+        environment: execution_context.environment.clone(),
+        depth: execution_context.depth,
+    });
+    Ok(State::Evaluate(value.downcast::<ResetValueInner>().inner.clone()))
+}
+
+fn step_shift(execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<ShiftValueInner>();
+    let (reset_index, reset_depth) = frames
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(index, frame)| match frame {
+            Frame::Reset { depth, .. } => Some((index, *depth)),
+            _ => None,
+        })
+        .ok_or_else(|| EvaluationError::new(value.clone(), EvaluationErrorReason::NoEnclosingReset))?;
+    let mut captured = frames.split_off(reset_index + 1);
+    // The captured frames may be resumed with other values, so results of the functions they are in must not be cached.
+    captured.retain(|frame| !matches!(frame, Frame::Memoize { .. }));
+    let continuation = Value::new(ContinuationValueInner {
+        frames: captured,
+        environment: execution_context.environment.clone(),
+        depth: execution_context.depth - reset_depth,
+    });
+    execution_context.depth = reset_depth;
+    Ok(State::Evaluate(Value::new(FunctionApplicationValueInner {
+        function: value_inner.handler.clone(),
+        arguments: Value::new(TupleValueInner { inner: vec![continuation] }),
+    })))
+}
+
+fn step_tuple(execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<TupleValueInner>();
+    // A value thrown or a loop exited on another thread continues unwinding here, as if the parts had been evaluated on this one.
+    // Exits stopped at the boundary of an application are reported with the application instead, and stay errors.
+    let parallel = match evaluate_in_parallel(execution_context, &value_inner.inner) {
+        Err(EvaluationError {
+            value,
+            reason: EvaluationErrorReason::UncaughtThrow { tag },
+        }) => return Ok(State::Unwind(Exit::Throw { tag, value })),
+        Err(EvaluationError {
+            value,
+            reason: EvaluationErrorReason::BreakOutsideLoop,
+        }) if value.is::<BreakValueInner>() => return Ok(State::Unwind(Exit::Break)),
+        Err(EvaluationError {
+            value,
+            reason: EvaluationErrorReason::ContinueOutsideLoop,
+        }) if value.is::<ContinueValueInner>() => return Ok(State::Unwind(Exit::Continue)),
+        parallel => parallel?,
+    };
+    Ok(if let Some(inner) = parallel {
         if inner == value_inner.inner {
-            value
+            State::ReturnSettled(value)
         } else {
-            Value::new(TupleValueInner { inner })
+            State::ReturnSettled(Value::new(TupleValueInner { inner }))
         }
     } else {
-        value
+        match value_inner.inner.first() {
+            Some(first) => {
+                let first = first.clone();
+                frames.push(Frame::Tuple {
+                    value,
+                    value_inner,
+                    inner: Vec::new(),
+                });
+                State::Evaluate(first)
+            }
+            None => State::Return(value),
+        }
+    })
+}
+
+fn step_association(_execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    let value_inner = value.downcast::<AssociationValueInner>();
+    Ok(match value_inner.inner.first() {
+        Some((first, _)) => {
+            let first = first.clone();
+            frames.push(Frame::Association {
+                value,
+                value_inner,
+                inner: Vec::new(),
+            });
+            State::Evaluate(first)
+        }
+        None => State::Return(value),
+    })
+}
+
+// The kinds evaluated by the machine itself, since they need its frames. Every other kind is evaluated through
+// `ValueKind::evaluate`.
+static MACHINE_STEPS: Lazy<HashMap<TypeId, StepFunction>> = Lazy::new(|| {
+    let mut steps = HashMap::new();
+    steps.insert(TypeId::of::<ReleaseValueInner>(), step_release as StepFunction);
+    steps.insert(TypeId::of::<AssignmentValueInner>(), step_assignment);
+    steps.insert(TypeId::of::<DereferenceValueInner>(), step_dereference);
+    steps.insert(TypeId::of::<ExecutableSequenceValueInner>(), step_executable_sequence);
+    steps.insert(TypeId::of::<ExecutableFunctionValueInner>(), step_executable_function);
+    steps.insert(TypeId::of::<IfValueInner>(), step_if);
+    steps.insert(TypeId::of::<FunctionApplicationValueInner>(), step_function_application);
+    steps.insert(TypeId::of::<IntrinsicCallValueInner>(), step_intrinsic_call);
+    steps.insert(TypeId::of::<ThrowValueInner>(), step_throw);
+    steps.insert(TypeId::of::<CatchValueInner>(), step_catch);
+    steps.insert(TypeId::of::<FinallyValueInner>(), step_finally);
+    steps.insert(TypeId::of::<WhileValueInner>(), step_while);
+    steps.insert(TypeId::of::<ForValueInner>(), step_for);
+    steps.insert(TypeId::of::<DoValueInner>(), step_do);
+    steps.insert(TypeId::of::<BreakValueInner>(), step_break);
+    steps.insert(TypeId::of::<ContinueValueInner>(), step_continue);
+    steps.insert(TypeId::of::<ResetValueInner>(), step_reset);
+    steps.insert(TypeId::of::<ShiftValueInner>(), step_shift);
+    steps.insert(TypeId::of::<TupleValueInner>(), step_tuple);
+    steps.insert(TypeId::of::<AssociationValueInner>(), step_association);
+    steps
+});
+
+fn take_step(frames: &mut Vec<Frame>, step: Step) -> State {
+    match step {
+        Step::Return(value) => State::Return(value),
+        Step::ReturnSettled(value) => State::ReturnSettled(value),
+        Step::EvaluateThen(value, resume) => {
+            frames.push(Frame::Resume { resume });
+            State::Evaluate(value)
+        }
+        Step::Throw { tag, value } => State::Unwind(Exit::Throw { tag, value }),
+    }
+}
+
+fn step(execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, value: Value) -> Result<State, EvaluationError> {
+    execution_context.check_budget(&value)?;
+    execution_context.notify(|observer, execution_context| observer.on_step_start(execution_context, &value));
+    if let Some(step) = MACHINE_STEPS.get(&value.inner_type_id()) {
+        return step(execution_context, frames, value);
+    }
+    let kind = value_kind::kind_of(&value).ok_or_else(|| EvaluationError::new(value.clone(), EvaluationErrorReason::UnknownKind))?;
+    Ok(take_step(frames, kind.evaluate(execution_context, &value)?))
+}
+
+// Parts can be evaluated concurrently when none of them changes state or does IO, since then none of them can observe the
+// others. Reading variables is fine, as nothing assigns them in the meantime. Inside a part evaluated in parallel everything is
+// independent already, since the effects inferred for the part cover everything evaluating it may do.
+#[cfg(feature = "parallel")]
+fn is_independent(execution_context: &mut ExecutionContext, value: &Value) -> bool {
+    if !execution_context.cancellations.is_empty() {
+        return true;
+    }
+    let effects = crate::effects::infer_effects_cached(execution_context, value);
+    !effects.writes_state && !effects.io
+}
+
+// Evaluates the parts on the thread pool if there is one and all of them are independent. Errors are reported in the order of the parts,
+// as if they were evaluated sequentially, and parts after a failed one are cancelled, so that they do not run on where a sequential
+// evaluation would have stopped.
+#[cfg(feature = "parallel")]
+fn evaluate_in_parallel(execution_context: &mut ExecutionContext, parts: &[Value]) -> Result<Option<Vec<Value>>, EvaluationError> {
+    use rayon::prelude::*;
+    let thread_pool = match &execution_context.thread_pool {
+        Some(thread_pool) if parts.len() > 1 && execution_context.observers.is_empty() => thread_pool.clone(),
+        _ => return Ok(None),
+    };
+    if !parts.iter().all(|part| is_independent(execution_context, part)) {
+        return Ok(None);
+    }
+    let first_failed = Arc::new(AtomicUsize::new(usize::MAX));
+    let forks = parts
+        .iter()
+        .enumerate()
+        .map(|(part, value)| {
+            let mut fork = execution_context.fork();
+            fork.cancellations.push(Cancellation {
+                first_failed: first_failed.clone(),
+                part,
+            });
+            (fork, part, value.clone())
+        })
+        .collect::<Vec<_>>();
+    let results = thread_pool.install(|| {
+        forks
+            .into_par_iter()
+            .map(|(mut fork, part, value)| {
+                let result = run(&mut fork, State::Evaluate(value));
+                match &result {
+                    // An interrupt is meant for the whole evaluation, so the earlier parts stop as well.
+                    Err(error) if !fork.is_cancelled() => {
+                        let part = if matches!(error.reason, EvaluationErrorReason::Interrupted) {
+                            0
+                        } else {
+                            part
+                        };
+                        first_failed.fetch_min(part, Ordering::SeqCst);
+                    }
+                    _ => {}
+                }
+                (result, fork)
+            })
+            .collect::<Vec<_>>()
+    });
+    let mut inner = Vec::new();
+    for (result, fork) in results {
+        execution_context.steps += fork.steps;
+        // Assignments in a fork would be lost, so the inferred effects must have ruled them out.
+        assert!(
+            Shared::ptr_eq(&fork.values, &execution_context.values) && Shared::ptr_eq(&fork.attributes, &execution_context.attributes),
+            "a part evaluated in parallel assigned a variable"
+        );
+        inner.push(result?);
+    }
+    // Each fork only checked its own steps against the budget, so the parts may have exceeded it together.
+    if matches!(execution_context.budget.max_steps, Some(max_steps) if execution_context.steps > max_steps) {
+        return Err(EvaluationError::new(
+            Value::new(TupleValueInner { inner: parts.to_vec() }),
+            EvaluationErrorReason::StepLimitExceeded,
+        ));
     }
+    Ok(Some(inner))
 }
 
-pub fn evaluate(execution_context: &mut ExecutionContext, mut value: Value) -> Value {
-    loop {
-        let new_value = evaluate_once(execution_context, value.clone());
-        if value == new_value {
-            return value;
+#[cfg(not(feature = "parallel"))]
+fn evaluate_in_parallel(_execution_context: &mut ExecutionContext, _parts: &[Value]) -> Result<Option<Vec<Value>>, EvaluationError> {
+    Ok(None)
+}
+
+fn resume(execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, frame: Frame, result: Value, settled: bool) -> Result<State, EvaluationError> {
+    Ok(match frame {
+        Frame::Fixpoint { value } => {
+            execution_context.notify(|observer, execution_context| observer.on_step(execution_context, &value, &result));
+            if value == result {
+                execution_context.depth -= 1;
+                State::ReturnSettled(value)
+            } else if settled {
+                execution_context.depth -= 1;
+                State::ReturnSettled(result)
+            } else {
+                frames.push(Frame::Fixpoint { value: result.clone() });
+                State::EvaluateOnce(result)
+            }
+        }
+        Frame::Release => State::Return(expect_kind::<HoldValueInner>(&result, "Hold")?.inner.clone()),
+        Frame::AssignmentSource { value_inner } => {
+            frames.push(Frame::AssignmentTarget { source: result });
+            State::Evaluate(value_inner.target.clone())
+        }
+        Frame::AssignmentTarget { source } => {
+            expect_kind::<SymbolValueInner>(&result, "Symbol")?;
+            execution_context.assign(result.clone(), source.clone());
+            execution_context.notify(|observer, execution_context| observer.on_assignment(execution_context, &result, &source));
+            State::ReturnSettled(Value::new(NullValueInner))
+        }
+        Frame::Dereference => {
+            expect_kind::<SymbolValueInner>(&result, "Symbol")?;
+            let value = execution_context.lookup(&result).unwrap_or_else(|| result.clone());
+            execution_context.notify(|observer, execution_context| observer.on_dereference(execution_context, &result, &value));
+            State::Return(value)
+        }
+        Frame::ExecutableSequence { value_inner, index } => match value_inner.inner.get(index) {
+            Some(next) => {
+                let next = next.clone();
+                // The last element is in tail position, so its result is the result of the sequence.
+                if index + 1 < value_inner.inner.len() {
+                    frames.push(Frame::ExecutableSequence { value_inner, index: index + 1 });
+                }
+                State::Evaluate(next)
+            }
+            None => State::ReturnSettled(result),
+        },
+        Frame::ExecutableFunctionArguments { value_inner } => {
+            frames.push(Frame::ExecutableFunctionBody { arguments: result });
+            State::Evaluate(value_inner.body.clone())
+        }
+        Frame::ExecutableFunctionBody { arguments } => State::ReturnSettled(Value::new(ClosureValueInner {
+            arguments,
+            body: result,
+            environment: execution_context.environment.clone(),
+        })),
+        Frame::If { value_inner } => {
+            if expect_kind::<BooleanValueInner>(&result, "Boolean")?.inner {
+                State::Return(value_inner.consequent.clone())
+            } else {
+                State::Return(value_inner.alternative.clone())
+            }
+        }
+        Frame::FunctionApplicationFunction { value, value_inner } => {
+            let (arguments, held) = hold_arguments(execution_context, &value_inner.function, value_inner.arguments.clone());
+            frames.push(Frame::FunctionApplicationArguments {
+                value,
+                value_inner,
+                function: result,
+                held,
+            });
+            State::Evaluate(arguments)
+        }
+        Frame::FunctionApplicationArguments {
+            value,
+            value_inner,
+            function,
+            held,
+        } => {
+            let arguments = release_arguments(&held, &value_inner.arguments, result)?;
+            let arguments = normalize_arguments(execution_context, &value_inner.function, arguments);
+            let threaded = thread_arguments(execution_context, &value_inner.function, &arguments, |arguments| {
+                Value::new(FunctionApplicationValueInner {
+                    function: value_inner.function.clone(),
+                    arguments,
+                })
+            })?;
+            if let Some(threaded) = threaded {
+                return Ok(State::Return(threaded));
+            }
+            if function.is::<ClosureValueInner>() {
+                match memo::lookup(execution_context, &function, &arguments) {
+                    memo::Lookup::Hit(result) => return Ok(State::Return(result)),
+                    memo::Lookup::Miss => match frames.last_mut() {
+                        Some(Frame::Memoize { applications }) => applications.push((function.clone(), arguments.clone())),
+                        _ => frames.push(Frame::Memoize {
+                            applications: vec![(function.clone(), arguments.clone())],
+                        }),
+                    },
+                    memo::Lookup::Uncacheable => {}
+                }
+            }
+            if let Some(continuation) = function.try_downcast::<ContinuationValueInner>() {
+                let arguments = expect_kind::<TupleValueInner>(&arguments, "Tuple")?.inner.clone();
+                if arguments.len() != 1 {
+                    return Err(EvaluationError::new(
+                        value,
+                        EvaluationErrorReason::ArgumentCountMismatch {
+                            expected: 1,
+                            actual: arguments.len(),
+                        },
+                    ));
+                }
+                frames.push(Frame::Reset {
+                    environment: execution_context.environment.clone(),
+                    depth: execution_context.depth,
+                });
+                frames.extend(continuation.frames.iter().cloned());
+                execution_context.environment = continuation.environment.clone();
+                execution_context.depth += continuation.depth;
+                return Ok(State::ReturnSettled(arguments[0].clone()));
+            }
+            #[allow(clippy::collapsible_if)]
+            if let Some(function) = function.try_downcast::<ClosureValueInner>() {
+                let arguments = expect_kind::<TupleValueInner>(&arguments, "Tuple")?.inner.clone();
+                let function_arguments = expect_kind::<TupleValueInner>(&function.arguments, "Tuple")?.inner.clone();
+                if function_arguments.len() != arguments.len() {
+                    return Err(EvaluationError::new(
+                        value,
+                        EvaluationErrorReason::ArgumentCountMismatch {
+                            expected: function_arguments.len(),
+                            actual: arguments.len(),
+                        },
+                    ));
+                }
+                let mut values = HashMap::new();
+                for (symbol, argument) in function_arguments.iter().cloned().zip(arguments) {
+                    expect_kind::<SymbolValueInner>(&symbol, "Symbol")?;
+                    values.insert(symbol, argument);
+                }
+                expect_kind::<HoldValueInner>(&function.body, "Hold")?;
+                let environment = Shared::new(Lock::new(Environment {
+                    values,
+                    parent: function.environment.clone(),
+                }));
+                // An application in tail position, separated from the environment restoration of the enclosing application
+                // only by fixpoint and memoization frames, replaces that application instead of nesting inside it, so that
+                // tail-recursive functions run in constant depth. The result of the body is settled, so the skipped fixpoints
+                // would only have passed it through. The memoized applications are merged into a frame below the restoration, so
+                // that the result is stored for every application in the chain once the last one returns.
+                if let Some(Frame::RestoreEnvironment { .. }) = frames
+                    .iter()
+                    .rev()
+                    .find(|frame| !matches!(frame, Frame::Fixpoint { .. } | Frame::Memoize { .. }))
+                {
+                    let mut memoized = Vec::new();
+                    loop {
+                        match frames.pop() {
+                            Some(Frame::Fixpoint { .. }) => execution_context.depth -= 1,
+                            Some(Frame::Memoize { applications }) => memoized.extend(applications),
+                            Some(frame) => {
+                                if !memoized.is_empty() {
+                                    match frames.last_mut() {
+                                        Some(Frame::Memoize { applications }) => applications.extend(memoized),
+                                        _ => frames.push(Frame::Memoize { applications: memoized }),
+                                    }
+                                }
+                                frames.push(frame);
+                                break;
+                            }
+                            None => unreachable!(),
+                        }
+                    }
+                    execution_context.environment = Some(environment);
+                } else {
+                    frames.push(Frame::RestoreEnvironment {
+                        environment: execution_context.environment.replace(environment),
+                    });
+                }
+                State::Evaluate(Value::new(ReleaseValueInner { inner: function.body.clone() }))
+            } else {
+                if function == value_inner.function && arguments == value_inner.arguments {
+                    State::ReturnSettled(value)
+                } else {
+                    State::ReturnSettled(Value::new(FunctionApplicationValueInner { function, arguments }))
+                }
+            }
+        }
+        Frame::Memoize { applications } => {
+            for (function, arguments) in applications {
+                memo::store(execution_context, &function, arguments, result.clone());
+            }
+            if settled {
+                State::ReturnSettled(result)
+            } else {
+                State::Return(result)
+            }
+        }
+        Frame::Throw { tag } => State::Unwind(Exit::Throw { tag, value: result }),
+        Frame::Catch { stack, .. } => {
+            execution_context.leave_stack_scope(stack);
+            if settled {
+                State::ReturnSettled(result)
+            } else {
+                State::Return(result)
+            }
+        }
+        Frame::Finally { value_inner, .. } => {
+            frames.push(Frame::FinallyReturn { result, settled });
+            State::Evaluate(value_inner.cleanup.clone())
+        }
+        Frame::FinallyReturn { result, settled } => {
+            if settled {
+                State::ReturnSettled(result)
+            } else {
+                State::Return(result)
+            }
+        }
+        Frame::FinallyResume { exit } => State::Unwind(exit),
+        Frame::Resume { resume } => take_step(frames, resume.resume(execution_context, result)?),
+        Frame::ForRange { value_inner } => {
+            let range = expect_kind::<TupleValueInner>(&result, "Tuple")?;
+            let start = number::expect_number(&range.inner[0])?;
+            let end = number::expect_number(&range.inner[1])?;
+            let step = number::expect_number(&range.inner[2])?;
+            let ascending = match number::compare(step.clone(), Number::Integer(BigInt::from(0))) {
+                Some(std::cmp::Ordering::Greater) => true,
+                Some(std::cmp::Ordering::Less) => false,
+                _ => return Err(EvaluationError::new(value_inner.step.clone(), EvaluationErrorReason::InvalidNumber)),
+            };
+            let iteration = Iteration::For {
+                value_inner,
+                next: start,
+                end,
+                step,
+                ascending,
+            };
+            let environment = execution_context.environment.clone();
+            let depth = execution_context.depth;
+            advance(execution_context, frames, iteration, environment, depth)?
+        }
+        Frame::DoElements { value_inner } => {
+            let elements = expect_kind::<TupleValueInner>(&result, "Tuple")?.inner.clone();
+            let iteration = Iteration::Do {
+                value_inner,
+                elements,
+                index: 0,
+            };
+            let environment = execution_context.environment.clone();
+            let depth = execution_context.depth;
+            advance(execution_context, frames, iteration, environment, depth)?
+        }
+        Frame::Loop {
+            iteration: Iteration::WhileCondition(value_inner),
+            environment,
+            depth,
+        } => {
+            if expect_kind::<BooleanValueInner>(&result, "Boolean")?.inner {
+                let body = value_inner.body.clone();
+                frames.push(Frame::Loop {
+                    iteration: Iteration::WhileBody(value_inner),
+                    environment,
+                    depth,
+                });
+                State::Evaluate(body)
+            } else {
+                State::ReturnSettled(Value::new(NullValueInner))
+            }
+        }
+        Frame::Loop { iteration, environment, depth } => advance(execution_context, frames, iteration, environment, depth)?,
+        // A shift may have discarded the frames that would have restored the environment.
+        Frame::Reset { environment, .. } => {
+            execution_context.environment = environment;
+            if settled {
+                State::ReturnSettled(result)
+            } else {
+                State::Return(result)
+            }
+        }
+        Frame::RestoreEnvironment { environment } => {
+            execution_context.environment = environment;
+            if settled {
+                State::ReturnSettled(result)
+            } else {
+                State::Return(result)
+            }
+        }
+        Frame::IntrinsicCallIntrinsic { value_inner } => {
+            let (arguments, held) = hold_arguments(execution_context, &value_inner.intrinsic, value_inner.arguments.clone());
+            frames.push(Frame::IntrinsicCallArguments {
+                value_inner,
+                intrinsic: result,
+                held,
+            });
+            State::Evaluate(arguments)
+        }
+        Frame::IntrinsicCallArguments { value_inner, intrinsic, held } => {
+            let arguments = release_arguments(&held, &value_inner.arguments, result)?;
+            let arguments = normalize_arguments(execution_context, &value_inner.intrinsic, arguments);
+            let threaded = thread_arguments(execution_context, &value_inner.intrinsic, &arguments, |arguments| {
+                Value::new(IntrinsicCallValueInner {
+                    intrinsic: value_inner.intrinsic.clone(),
+                    arguments,
+                })
+            })?;
+            if let Some(threaded) = threaded {
+                return Ok(State::Return(threaded));
+            }
+            let arguments = expect_kind::<TupleValueInner>(&arguments, "Tuple")?.inner.clone();
+            let function = execution_context
+                .intrinsics
+                .get(&intrinsic)
+                .ok_or_else(|| EvaluationError::new(intrinsic.clone(), EvaluationErrorReason::UnknownIntrinsic))?
+                .function;
+            if execution_context.observers.is_empty() {
+                State::Return(function(execution_context, arguments)?)
+            } else {
+                execution_context.notify(|observer, execution_context| observer.on_intrinsic_call_start(execution_context, &intrinsic, &arguments));
+                let result = function(execution_context, arguments.clone())?;
+                execution_context.notify(|observer, execution_context| observer.on_intrinsic_call(execution_context, &intrinsic, &arguments, &result));
+                State::Return(result)
+            }
+        }
+        Frame::Tuple { value, value_inner, mut inner } => {
+            inner.push(result);
+            match value_inner.inner.get(inner.len()) {
+                Some(next) => {
+                    let next = next.clone();
+                    frames.push(Frame::Tuple { value, value_inner, inner });
+                    State::Evaluate(next)
+                }
+                None if inner == value_inner.inner => State::ReturnSettled(value),
+                None => State::ReturnSettled(Value::new(TupleValueInner { inner })),
+            }
         }
-        value = new_value;
+        Frame::Association { value, value_inner, mut inner } => {
+            inner.push(result);
+            match value_inner.inner.get(inner.len() / 2) {
+                Some((next_key, _)) if inner.len() % 2 == 0 => {
+                    let next = next_key.clone();
+                    frames.push(Frame::Association { value, value_inner, inner });
+                    State::Evaluate(next)
+                }
+                Some((_, next_value)) => {
+                    let next = next_value.clone();
+                    frames.push(Frame::Association { value, value_inner, inner });
+                    State::Evaluate(next)
+                }
+                None if inner == value_inner.parts() => State::ReturnSettled(value),
+                None => {
+                    let mut association = AssociationValueInner { inner: Vec::new() };
+                    for pair in inner.chunks(2) {
+                        association.insert(pair[0].clone(), pair[1].clone());
+                    }
+                    State::ReturnSettled(Value::new(association))
+                }
+            }
+        }
+    })
+}
+
+// Starts the next iteration of a loop, whose previous iteration, if any, has finished. The loop variable is bound in a fresh
+// environment for every iteration, so that closures created in the body keep the value of their own iteration.
+fn advance(
+    execution_context: &mut ExecutionContext,
+    frames: &mut Vec<Frame>,
+    iteration: Iteration,
+    environment: Option<Shared<Lock<Environment>>>,
+    depth: usize,
+) -> Result<State, EvaluationError> {
+    execution_context.environment = environment.clone();
+    let (iteration, variable, element, body) = match iteration {
+        Iteration::WhileCondition(value_inner) | Iteration::WhileBody(value_inner) => {
+            let condition = value_inner.condition.clone();
+            frames.push(Frame::Loop {
+                iteration: Iteration::WhileCondition(value_inner),
+                environment,
+                depth,
+            });
+            return Ok(State::Evaluate(condition));
+        }
+        Iteration::For {
+            value_inner,
+            next,
+            end,
+            step,
+            ascending,
+        } => {
+            let finished = match number::compare(next.clone(), end.clone()) {
+                Some(std::cmp::Ordering::Greater) => ascending,
+                Some(std::cmp::Ordering::Less) => !ascending,
+                Some(std::cmp::Ordering::Equal) => false,
+                None => true,
+            };
+            if finished {
+                return Ok(State::ReturnSettled(Value::new(NullValueInner)));
+            }
+            let (variable, body) = (value_inner.variable.clone(), value_inner.body.clone());
+            let element = next.clone().into_value();
+            let iteration = Iteration::For {
+                value_inner,
+                next: number::add(next, step.clone()),
+                end,
+                step,
+                ascending,
+            };
+            (iteration, variable, element, body)
+        }
+        Iteration::Do { value_inner, elements, index } => {
+            let element = match elements.get(index) {
+                Some(element) => element.clone(),
+                None => return Ok(State::ReturnSettled(Value::new(NullValueInner))),
+            };
+            let (variable, body) = (value_inner.variable.clone(), value_inner.body.clone());
+            let iteration = Iteration::Do {
+                value_inner,
+                elements,
+                index: index + 1,
+            };
+            (iteration, variable, element, body)
+        }
+    };
+    execution_context.environment = Some(Shared::new(Lock::new(Environment {
+        values: std::iter::once((variable, element)).collect(),
+        parent: environment.clone(),
+    })));
+    frames.push(Frame::Loop { iteration, environment, depth });
+    Ok(State::Evaluate(body))
+}
+
+fn catches(catch_tag: &Value, exit: &Exit) -> bool {
+    match exit {
+        Exit::Throw { tag, .. } => catch_tag.is::<NullValueInner>() || structural::structural_eq(catch_tag, tag),
+        _ => false,
     }
 }
 
-pub fn replace(value: Value, from: Value, to: Value) -> Value {
-    if value == from {
-        to
-    } else if let Some(value_inner) = value.try_downcast::<HoldValueInner>() {
-        let inner = replace(value_inner.inner.clone(), from, to);
-        if inner == value_inner.inner {
-            value
-        } else {
-            Value::new(HoldValueInner { inner })
+// Pops frames up to the innermost one handling the exit, restoring the state it saved: a `Catch` for the tag of a throw, a loop
+// for a break or continue, or any `Finally`, which resumes the exit after its cleanup. Values pushed on the stack since entering
+// a `Catch` are popped and values popped since then are pushed back, while a `Finally` leaves the stack to its cleanup. A break or
+// continue never leaves the function it is in, so reaching the application boundary reports it with the application.
+fn unwind(execution_context: &mut ExecutionContext, frames: &mut Vec<Frame>, exit: Exit) -> Result<State, EvaluationError> {
+    while let Some(frame) = frames.pop() {
+        match frame {
+            Frame::Catch {
+                value_inner,
+                environment,
+                depth,
+                stack,
+            } if catches(&value_inner.tag, &exit) => {
+                execution_context.environment = environment;
+                execution_context.depth = depth;
+                execution_context.restore_stack_scope(stack);
+                let value = match exit {
+                    Exit::Throw { value, .. } => value,
+                    _ => unreachable!(),
+                };
+                return Ok(State::Evaluate(Value::new(FunctionApplicationValueInner {
+                    function: value_inner.handler.clone(),
+                    arguments: Value::new(TupleValueInner { inner: vec![value] }),
+                })));
+            }
+            Frame::Finally {
+                value_inner,
+                environment,
+                depth,
+            } => {
+                execution_context.environment = environment;
+                execution_context.depth = depth;
+                frames.push(Frame::FinallyResume { exit });
+                return Ok(State::Evaluate(value_inner.cleanup.clone()));
+            }
+            Frame::Loop { iteration, environment, depth } if !matches!(exit, Exit::Throw { .. }) => {
+                execution_context.environment = environment.clone();
+                execution_context.depth = depth;
+                return match exit {
+                    Exit::Continue => advance(execution_context, frames, iteration, environment, depth),
+                    _ => Ok(State::ReturnSettled(Value::new(NullValueInner))),
+                };
+            }
+            Frame::RestoreEnvironment { .. } if !matches!(exit, Exit::Throw { .. }) => {
+                let application = frames.iter().rev().find_map(|frame| match frame {
+                    Frame::Fixpoint { value } => Some(value.clone()),
+                    _ => None,
+                });
+                return Err(match exit {
+                    Exit::Break => EvaluationError::new(
+                        application.unwrap_or_else(|| Value::new(BreakValueInner)),
+                        EvaluationErrorReason::BreakOutsideLoop,
+                    ),
+                    _ => EvaluationError::new(
+                        application.unwrap_or_else(|| Value::new(ContinueValueInner)),
+                        EvaluationErrorReason::ContinueOutsideLoop,
+                    ),
+                });
+            }
+            Frame::Catch { stack, .. } => execution_context.leave_stack_scope(stack),
+            _ => {}
         }
-    } else if let Some(value_inner) = value.try_downcast::<ReleaseValueInner>() {
-        let inner = replace(value_inner.inner.clone(), from, to);
-        if inner == value_inner.inner {
-            value
-        } else {
-            Value::new(ReleaseValueInner { inner })
+    }
+    Err(match exit {
+        Exit::Throw { tag, value } => EvaluationError::new(value, EvaluationErrorReason::UncaughtThrow { tag }),
+        Exit::Break => EvaluationError::new(Value::new(BreakValueInner), EvaluationErrorReason::BreakOutsideLoop),
+        Exit::Continue => EvaluationError::new(Value::new(ContinueValueInner), EvaluationErrorReason::ContinueOutsideLoop),
+    })
+}
+
+fn run(execution_context: &mut ExecutionContext, mut state: State) -> Result<Value, EvaluationError> {
+    if execution_context.depth == 0 {
+        execution_context.steps = 0;
+    }
+    let saved_environment = execution_context.environment.clone();
+    let saved_depth = execution_context.depth;
+    // Catches left by an error are not left through their frames.
+    let stack_scope = execution_context.stack_scope();
+    let mut frames = Vec::new();
+    let result = loop {
+        let next = match state {
+            State::Evaluate(value) => {
+                frames.push(Frame::Fixpoint { value: value.clone() });
+                execution_context.depth += 1;
+                step(execution_context, &mut frames, value)
+            }
+            State::EvaluateOnce(value) => step(execution_context, &mut frames, value),
+            State::Return(value) => match frames.pop() {
+                Some(frame) => resume(execution_context, &mut frames, frame, value, false),
+                None => break Ok(value),
+            },
+            State::ReturnSettled(value) => match frames.pop() {
+                Some(frame) => resume(execution_context, &mut frames, frame, value, true),
+                None => break Ok(value),
+            },
+            State::Unwind(exit) => unwind(execution_context, &mut frames, exit),
+        };
+        match next {
+            Ok(next) => state = next,
+            Err(error) => break Err(error),
         }
-    } else if let Some(value_inner) = value.try_downcast::<AssignmentValueInner>() {
-        let source = replace(value_inner.source.clone(), from.clone(), to.clone());
-        let target = replace(value_inner.target.clone(), from, to);
-        if source == value_inner.source && target == value_inner.target {
-            value
-        } else {
-            Value::new(AssignmentValueInner { source, target })
+    };
+    result.map_err(|mut error| {
+        if error.reason.is_limit() {
+            if let Some(Frame::Fixpoint { value }) = frames.iter().find(|frame| matches!(frame, Frame::Fixpoint { .. })) {
+                error.value = value.clone();
+            }
         }
-    } else if let Some(value_inner) = value.try_downcast::<DereferenceValueInner>() {
-        let inner = replace(value_inner.inner.clone(), from, to);
-        if inner == value_inner.inner {
-            value
-        } else {
-            Value::new(DereferenceValueInner { inner })
+        execution_context.environment = saved_environment;
+        execution_context.depth = saved_depth;
+        execution_context.leave_stack_scope(stack_scope);
+        error
+    })
+}
+
+pub fn evaluate(execution_context: &mut ExecutionContext, value: Value) -> Result<Value, EvaluationError> {
+    let result = run(execution_context, State::Evaluate(value))?;
+    Ok(match &mut execution_context.interner {
+        Some(interner) => interner.intern(result),
+        None => result,
+    })
+}
+
+pub fn get_parts(value: Value) -> Vec<Value> {
+    value_kind::kind_or_unregistered(&value).parts(&value)
+}
+
+pub fn replace_parts(value: Value, parts: &[Value]) -> Value {
+    value_kind::kind_or_unregistered(&value).replace_parts(value, parts)
+}
+
+pub fn kind_name(value: &Value) -> &'static str {
+    value_kind::kind_or_unregistered(value).name()
+}
+
+pub fn equal(a: &Value, b: &Value) -> bool {
+    if let (Some(a), Some(b)) = (Number::from_value(a), Number::from_value(b)) {
+        number::compare(a, b) == Some(std::cmp::Ordering::Equal)
+    } else if let (Some(a), Some(b)) = (a.try_downcast::<StringValueInner>(), b.try_downcast::<StringValueInner>()) {
+        a.inner == b.inner
+    } else if let (Some(a), Some(b)) = (a.try_downcast::<BooleanValueInner>(), b.try_downcast::<BooleanValueInner>()) {
+        a.inner == b.inner
+    } else {
+        structural::structural_eq(a, b)
+    }
+}
+
+fn fresh_symbol(symbol: &SymbolValueInner) -> Value {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    Value::new(SymbolValueInner {
+        name: format!("{}${}", symbol.name, COUNTER.fetch_add(1, Ordering::Relaxed)),
+        fresh: true,
+    })
+}
+
+fn get_binders(value: &Value) -> Option<Vec<Value>> {
+    let arguments = match value.try_downcast::<ExecutableFunctionValueInner>() {
+        Some(value_inner) => value_inner.arguments.clone(),
+        None => value.try_downcast::<ClosureValueInner>()?.arguments.clone(),
+    };
+    Some(
+        arguments
+            .try_downcast::<TupleValueInner>()
+            .map(|arguments| arguments.inner.iter().filter(|x| x.is::<SymbolValueInner>()).cloned().collect())
+            .unwrap_or_default(),
+    )
+}
+
+pub enum Rewrite {
+    Replace(Value),
+    Descend(Value),
+}
+
+pub fn rewrite<F: FnMut(Value) -> Rewrite>(value: Value, mut f: F) -> Value {
+    enum Task {
+        Visit(Value),
+        Rebuild(Value, usize),
+    }
+    let mut tasks = vec![Task::Visit(value)];
+    let mut results = Vec::new();
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Visit(value) => match f(value) {
+                Rewrite::Replace(value) => results.push(value),
+                Rewrite::Descend(value) => {
+                    let parts = get_parts(value.clone());
+                    if parts.is_empty() {
+                        results.push(value);
+                    } else {
+                        tasks.push(Task::Rebuild(value, parts.len()));
+                        tasks.extend(parts.into_iter().rev().map(Task::Visit));
+                    }
+                }
+            },
+            Task::Rebuild(value, count) => {
+                let parts = results.split_off(results.len() - count);
+                results.push(replace_parts(value, &parts));
+            }
         }
-    } else if let Some(value_inner) = value.try_downcast::<ExecutableSequenceValueInner>() {
-        let inner = value_inner.inner.iter().map(|x| replace(x.clone(), from.clone(), to.clone())).collect();
-        if inner == value_inner.inner {
-            value
-        } else {
-            Value::new(ExecutableSequenceValueInner { inner })
+    }
+    results.pop().unwrap()
+}
+
+pub fn free_symbols(value: Value) -> HashSet<Value> {
+    enum Task {
+        Visit(Value),
+        Collect(Option<Vec<Value>>, usize),
+    }
+    let mut tasks = vec![Task::Visit(value)];
+    let mut results: Vec<HashSet<Value>> = Vec::new();
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Visit(value) => {
+                if value.is::<SymbolValueInner>() {
+                    results.push(std::iter::once(value).collect());
+                } else if let Some(binders) = get_binders(&value) {
+                    tasks.push(Task::Collect(Some(binders), 1));
+                    tasks.push(Task::Visit(get_parts(value)[1].clone()));
+                } else {
+                    let parts = get_parts(value);
+                    tasks.push(Task::Collect(None, parts.len()));
+                    tasks.extend(parts.into_iter().map(Task::Visit));
+                }
+            }
+            Task::Collect(binders, count) => {
+                let mut result = HashSet::new();
+                for part in results.drain(results.len() - count..) {
+                    result.extend(part);
+                }
+                for binder in binders.into_iter().flatten() {
+                    result.remove(&binder);
+                }
+                results.push(result);
+            }
         }
-    } else if let Some(value_inner) = value.try_downcast::<ExecutableFunctionValueInner>() {
-        let arguments = replace(value_inner.arguments.clone(), from.clone(), to.clone());
-        let body = replace(value_inner.body.clone(), from, to);
-        if arguments == value_inner.arguments && body == value_inner.body {
-            value
+    }
+    results.pop().unwrap()
+}
+
+pub fn replace_capture_avoiding(value: Value, from: Value, to: Value) -> Value {
+    let free_in_to = free_symbols(to.clone());
+    rewrite(value, |value| {
+        if value == from {
+            Rewrite::Replace(to.clone())
+        } else if let Some(binders) = get_binders(&value) {
+            let parts = get_parts(value.clone());
+            if binders.contains(&from) || !free_symbols(parts[1].clone()).contains(&from) {
+                return Rewrite::Replace(value);
+            }
+            let mut arguments = parts[0].clone();
+            let mut body = parts[1].clone();
+            for binder in binders.into_iter().filter(|binder| free_in_to.contains(binder)) {
+                let renamed = fresh_symbol(&binder.downcast::<SymbolValueInner>());
+                arguments = replace(arguments, binder.clone(), renamed.clone());
+                body = replace(body, binder, renamed);
+            }
+            Rewrite::Descend(replace_parts(value, &[arguments, body]))
         } else {
-            Value::new(ExecutableFunctionValueInner { arguments, body })
+            Rewrite::Descend(value)
         }
-    } else if let Some(value_inner) = value.try_downcast::<FunctionApplicationValueInner>() {
-        let function = replace(value_inner.function.clone(), from.clone(), to.clone());
-        let arguments = replace(value_inner.arguments.clone(), from, to);
-        if function == value_inner.function && arguments == value_inner.arguments {
-            value
+    })
+}
+
+pub fn replace(value: Value, from: Value, to: Value) -> Value {
+    rewrite(value, |value| {
+        if value == from {
+            Rewrite::Replace(to.clone())
         } else {
-            Value::new(FunctionApplicationValueInner { function, arguments })
+            Rewrite::Descend(value)
         }
-    } else if let Some(value_inner) = value.try_downcast::<IntrinsicCallValueInner>() {
-        let intrinsic = replace(value_inner.intrinsic.clone(), from.clone(), to.clone());
-        let arguments = replace(value_inner.arguments.clone(), from, to);
-        if intrinsic == value_inner.intrinsic && arguments == value_inner.arguments {
-            value
-        } else {
-            Value::new(IntrinsicCallValueInner { intrinsic, arguments })
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn symbol(name: &str) -> Value {
+        Value::new(SymbolValueInner {
+            name: name.to_owned(),
+            fresh: false,
+        })
+    }
+
+    pub(crate) fn integer(inner: i64) -> Value {
+        Value::new(IntegerValueInner { inner: BigInt::from(inner) })
+    }
+
+    pub(crate) fn tuple(inner: Vec<Value>) -> Value {
+        Value::new(TupleValueInner { inner })
+    }
+
+    pub(crate) fn dereference(inner: &Value) -> Value {
+        Value::new(DereferenceValueInner { inner: inner.clone() })
+    }
+
+    pub(crate) fn intrinsic_call(intrinsic: &Value, arguments: Vec<Value>) -> Value {
+        Value::new(IntrinsicCallValueInner {
+            intrinsic: intrinsic.clone(),
+            arguments: tuple(arguments),
+        })
+    }
+
+    pub(crate) fn intrinsic_add(_execution_context: &mut ExecutionContext, arguments: Vec<Value>) -> Result<Value, EvaluationError> {
+        Ok(number::add(number::expect_number(&arguments[0])?, number::expect_number(&arguments[1])?).into_value())
+    }
+
+    pub(crate) fn intrinsic_subtract(_execution_context: &mut ExecutionContext, arguments: Vec<Value>) -> Result<Value, EvaluationError> {
+        Ok(number::subtract(number::expect_number(&arguments[0])?, number::expect_number(&arguments[1])?).into_value())
+    }
+
+    pub(crate) fn intrinsic_equal(_execution_context: &mut ExecutionContext, arguments: Vec<Value>) -> Result<Value, EvaluationError> {
+        Ok(Value::new(BooleanValueInner {
+            inner: equal(&arguments[0], &arguments[1]),
+        }))
+    }
+
+    // A context with the pure intrinsics add, subtract and equal.
+    pub(crate) fn arithmetic_context() -> ExecutionContext {
+        let mut intrinsics = HashMap::new();
+        let functions: [(&str, IntrinsicFunction); 3] = [("add", intrinsic_add), ("subtract", intrinsic_subtract), ("equal", intrinsic_equal)];
+        for (name, function) in functions.iter() {
+            intrinsics.insert(
+                symbol(name),
+                Intrinsic {
+                    function: *function,
+                    effects: Effects::PURE,
+                },
+            );
         }
-    } else if let Some(value_inner) = value.try_downcast::<TupleValueInner>() {
-        let inner = value_inner.inner.iter().map(|x| replace(x.clone(), from.clone(), to.clone())).collect();
-        if inner == value_inner.inner {
-            value
-        } else {
-            Value::new(TupleValueInner { inner })
+        ExecutionContext::new(intrinsics)
+    }
+
+    pub(crate) fn function(arguments: Vec<Value>, body: Value) -> Value {
+        Value::new(ExecutableFunctionValueInner {
+            arguments: tuple(arguments),
+            body: Value::new(HoldValueInner { inner: body }),
+        })
+    }
+
+    pub(crate) fn apply(function: &Value, arguments: Vec<Value>) -> Value {
+        Value::new(FunctionApplicationValueInner {
+            function: dereference(function),
+            arguments: tuple(arguments),
+        })
+    }
+
+    pub(crate) fn assign(target: &Value, source: Value) -> Value {
+        Value::new(AssignmentValueInner {
+            target: target.clone(),
+            source,
+        })
+    }
+
+    pub(crate) fn sequence(inner: Vec<Value>) -> Value {
+        Value::new(ExecutableSequenceValueInner { inner })
+    }
+
+    pub(crate) fn for_range(variable: &Value, start: i64, end: i64, step: i64, body: Value) -> Value {
+        Value::new(ForValueInner {
+            variable: variable.clone(),
+            start: integer(start),
+            end: integer(end),
+            step: integer(step),
+            body,
+        })
+    }
+
+    pub(crate) fn when(condition: Value, consequent: Value) -> Value {
+        Value::new(IfValueInner {
+            condition,
+            consequent,
+            alternative: Value::new(NullValueInner),
+        })
+    }
+
+    pub(crate) fn intrinsic_push(execution_context: &mut ExecutionContext, arguments: Vec<Value>) -> Result<Value, EvaluationError> {
+        execution_context.push(arguments[0].clone());
+        Ok(Value::new(NullValueInner))
+    }
+
+    pub(crate) fn intrinsic_pop(execution_context: &mut ExecutionContext, _arguments: Vec<Value>) -> Result<Value, EvaluationError> {
+        Ok(execution_context.pop().unwrap_or_else(|| Value::new(NullValueInner)))
+    }
+
+    pub(crate) fn throw(tag: &Value, inner: Value) -> Value {
+        Value::new(ThrowValueInner { tag: tag.clone(), inner })
+    }
+
+    pub(crate) fn catch(tag: Value, inner: Value, handler: Value) -> Value {
+        Value::new(CatchValueInner { tag, inner, handler })
+    }
+
+    // A bad expression is reported with the value that failed, and leaves the context as it was before the evaluation.
+    #[test]
+    fn evaluation_errors() {
+        let add = symbol("add");
+        let mut execution_context = arithmetic_context();
+        let (f, x, missing) = (symbol("f"), symbol("x"), symbol("missing"));
+        evaluate(&mut execution_context, assign(&f, function(vec![x.clone()], dereference(&x)))).unwrap();
+        let release = Value::new(ReleaseValueInner { inner: integer(1) });
+        let error = evaluate(&mut execution_context, release).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::UnexpectedKind { expected: "Hold" }));
+        assert!(super::equal(&error.value, &integer(1)));
+        let error = evaluate(&mut execution_context, assign(&integer(1), integer(2))).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::UnexpectedKind { expected: "Symbol" }));
+        let error = evaluate(&mut execution_context, apply(&f, vec![integer(1), integer(2)])).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::ArgumentCountMismatch { expected: 1, actual: 2 }));
+        let error = evaluate(&mut execution_context, intrinsic_call(&missing, vec![])).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::UnknownIntrinsic));
+        assert_eq!(error.to_string(), "unknown intrinsic");
+        // An intrinsic failing inside a function body.
+        let text = Value::new(StringValueInner { inner: "one".to_owned() });
+        let g = symbol("g");
+        evaluate(
+            &mut execution_context,
+            assign(&g, function(vec![x.clone()], intrinsic_call(&add, vec![dereference(&x), text.clone()]))),
+        )
+        .unwrap();
+        let error = evaluate(&mut execution_context, apply(&g, vec![integer(1)])).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::UnexpectedKind { expected: "Number" }));
+        assert!(structural::structural_eq(&error.value, &text));
+        assert!(execution_context.environment.is_none());
+        assert_eq!(execution_context.depth, 0);
+        let result = evaluate(&mut execution_context, apply(&f, vec![integer(3)])).unwrap();
+        assert!(super::equal(&result, &integer(3)));
+    }
+
+    // Evaluating, rewriting, hashing, comparing and dropping a tree must not recurse on the Rust stack, so a thread with a small
+    // stack can handle a tree a million levels deep.
+    #[test]
+    fn deeply_nested_values() {
+        std::thread::Builder::new()
+            .stack_size(1 << 20)
+            .spawn(|| {
+                let x = symbol("x");
+                let leaf = dereference(&x);
+                let mut value = leaf.clone();
+                for _ in 0..1_000_000 {
+                    value = tuple(vec![value]);
+                }
+                let mut execution_context = ExecutionContext::new(HashMap::new());
+                execution_context.assign(x.clone(), integer(1));
+                let result = evaluate(&mut execution_context, value.clone()).unwrap();
+                let replaced = replace(value.clone(), leaf, integer(1));
+                assert!(structural::structural_eq(&result, &replaced));
+                assert_eq!(structural::structural_hash(&result), structural::structural_hash(&replaced));
+                assert_eq!(ordering::compare(&result, &replaced), std::cmp::Ordering::Equal);
+                assert_eq!(ordering::compare(&value, &result), ordering::compare(&value, &replaced));
+                let mut depth = 0;
+                let mut current = result.clone();
+                while let Some(current_inner) = current.try_downcast::<TupleValueInner>() {
+                    current = current_inner.inner[0].clone();
+                    depth += 1;
+                }
+                assert_eq!(depth, 1_000_000);
+                drop((value, result, replaced));
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn replace_repeated_with_idempotent_rule() {
+        let f = symbol("f");
+        let x = symbol("x");
+        // f(x_) -> f(x)
+        let rule = Value::new(RuleValueInner {
+            left: Value::new(FunctionApplicationValueInner {
+                function: f.clone(),
+                arguments: tuple(vec![Value::new(PatternValueInner {
+                    name: x.clone(),
+                    inner: Value::new(BlankValueInner { kind: None }),
+                })]),
+            }),
+            right: Value::new(FunctionApplicationValueInner {
+                function: f.clone(),
+                arguments: tuple(vec![x]),
+            }),
+        });
+        let value = Value::new(FunctionApplicationValueInner {
+            function: f,
+            arguments: tuple(vec![integer(1)]),
+        });
+        let mut execution_context = ExecutionContext::new(HashMap::new());
+        let result = crate::pattern_matching::replace_repeated(&mut execution_context, value.clone(), &[rule]).unwrap();
+        assert!(structural::structural_eq(&result, &value));
+    }
+
+    #[test]
+    fn fresh_symbols_do_not_collide() {
+        let x = symbol("x");
+        let y = symbol("y");
+        // (y) -> (x, y) with x replaced by y renames the binder.
+        let function = Value::new(ExecutableFunctionValueInner {
+            arguments: tuple(vec![y.clone()]),
+            body: tuple(vec![x.clone(), y.clone()]),
+        });
+        let result = replace_capture_avoiding(function, x, y.clone());
+        let result_inner = result.downcast::<ExecutableFunctionValueInner>();
+        let renamed = result_inner.arguments.downcast::<TupleValueInner>().inner[0].clone();
+        assert!(renamed != y);
+        // A symbol written with the same name as the fresh one is a different symbol.
+        let written = symbol(&renamed.downcast::<SymbolValueInner>().name);
+        assert!(renamed != written);
+        assert!(!structural::structural_eq(&renamed, &written));
+        let replaced = replace_capture_avoiding(result.clone(), written, integer(0));
+        assert!(structural::structural_eq(&replaced, &result));
+        assert!(free_symbols(result) == std::iter::once(y).collect());
+    }
+
+    // A continuation resumes the rest of its reset each time it is called, also once the reset has returned.
+    #[test]
+    fn call_captured_continuations() {
+        let add = symbol("add");
+        let mut execution_context = arithmetic_context();
+        let (k, saved) = (symbol("k"), symbol("saved"));
+        let reset = |inner: Value| Value::new(ResetValueInner { inner });
+        let shift = |handler: Value| Value::new(ShiftValueInner { handler });
+        let twice = function(vec![k.clone()], apply(&k, vec![apply(&k, vec![integer(10)])]));
+        let program = reset(intrinsic_call(&add, vec![integer(1), shift(twice)]));
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(super::equal(&result, &integer(12)));
+        assert!(execution_context.environment.is_none());
+        assert_eq!(execution_context.depth, 0);
+        let save = function(vec![k.clone()], sequence(vec![assign(&saved, dereference(&k)), integer(0)]));
+        let program = reset(intrinsic_call(&add, vec![integer(1), shift(save)]));
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(super::equal(&result, &integer(0)));
+        let result = evaluate(&mut execution_context, apply(&saved, vec![integer(5)])).unwrap();
+        assert!(super::equal(&result, &integer(6)));
+        assert!(execution_context.environment.is_none());
+        assert_eq!(execution_context.depth, 0);
+    }
+
+    fn intrinsic_first(_execution_context: &mut ExecutionContext, arguments: Vec<Value>) -> Result<Value, EvaluationError> {
+        Ok(arguments[0].clone())
+    }
+
+    fn intrinsic_hold_first(execution_context: &mut ExecutionContext, _arguments: Vec<Value>) -> Result<Value, EvaluationError> {
+        Shared::make_mut(&mut execution_context.attributes)
+            .entry(symbol("first"))
+            .or_default()
+            .insert(Attribute::HoldAll);
+        Ok(Value::new(NullValueInner))
+    }
+
+    // The attributes of the head change while its arguments are evaluated, which must not affect which of them are released.
+    #[test]
+    fn attributes_changed_by_arguments() {
+        let first = symbol("first");
+        let hold_first = symbol("hold_first");
+        let mut intrinsics = HashMap::new();
+        intrinsics.insert(
+            first.clone(),
+            Intrinsic {
+                function: intrinsic_first,
+                effects: Effects::PURE,
+            },
+        );
+        intrinsics.insert(
+            hold_first.clone(),
+            Intrinsic {
+                function: intrinsic_hold_first,
+                effects: Effects::WRITES_STATE,
+            },
+        );
+        let mut execution_context = ExecutionContext::new(intrinsics);
+        let value = intrinsic_call(&first, vec![intrinsic_call(&hold_first, vec![]), integer(2)]);
+        let result = evaluate(&mut execution_context, value).unwrap();
+        assert!(result.is::<NullValueInner>());
+        assert!(execution_context.has_attribute(&first, Attribute::HoldAll));
+    }
+
+    #[test]
+    fn tail_recursive_countdown() {
+        let (add, subtract, equal) = (symbol("add"), symbol("subtract"), symbol("equal"));
+        let mut execution_context = arithmetic_context();
+        // Without tail calls every iteration would nest one application deeper.
+        execution_context.budget.max_depth = Some(100);
+        let countdown = symbol("countdown");
+        let n = symbol("n");
+        let steps = symbol("steps");
+        let body = Value::new(ExecutableSequenceValueInner {
+            inner: vec![
+                Value::new(AssignmentValueInner {
+                    target: steps.clone(),
+                    source: intrinsic_call(&add, vec![dereference(&steps), integer(1)]),
+                }),
+                Value::new(IfValueInner {
+                    condition: intrinsic_call(&equal, vec![dereference(&n), integer(0)]),
+                    consequent: Value::new(StringValueInner { inner: "done".to_owned() }),
+                    alternative: Value::new(FunctionApplicationValueInner {
+                        function: dereference(&countdown),
+                        arguments: tuple(vec![intrinsic_call(&subtract, vec![dereference(&n), integer(1)])]),
+                    }),
+                }),
+            ],
+        });
+        let program = Value::new(ExecutableSequenceValueInner {
+            inner: vec![
+                Value::new(AssignmentValueInner {
+                    target: steps.clone(),
+                    source: integer(0),
+                }),
+                Value::new(AssignmentValueInner {
+                    target: countdown.clone(),
+                    source: Value::new(ExecutableFunctionValueInner {
+                        arguments: tuple(vec![n]),
+                        body: Value::new(HoldValueInner { inner: body }),
+                    }),
+                }),
+                Value::new(FunctionApplicationValueInner {
+                    function: dereference(&countdown),
+                    arguments: tuple(vec![integer(1_000_000)]),
+                }),
+            ],
+        });
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert_eq!(expect_kind::<StringValueInner>(&result, "String").unwrap().inner, "done");
+        assert!(super::equal(&execution_context.values[&steps], &integer(1_000_001)));
+        assert!(execution_context.environment.is_none());
+        assert_eq!(execution_context.depth, 0);
+    }
+
+    // A break or continue in a function applied in a loop does not exit the loop, whether the application is evaluated here or,
+    // as a part of a tuple, on another thread.
+    #[test]
+    fn loop_exits_stop_at_applications() {
+        let mut execution_context = arithmetic_context();
+        let (f, i, count) = (symbol("f"), symbol("i"), symbol("count"));
+        let check = |execution_context: &mut ExecutionContext| {
+            let exits = [
+                (Value::new(BreakValueInner), EvaluationErrorReason::BreakOutsideLoop),
+                (Value::new(ContinueValueInner), EvaluationErrorReason::ContinueOutsideLoop),
+            ];
+            for (exit, reason) in &exits {
+                let program = sequence(vec![
+                    assign(&f, function(vec![], exit.clone())),
+                    for_range(&i, 1, 3, 1, tuple(vec![apply(&f, vec![]), integer(1)])),
+                ]);
+                let error = evaluate(execution_context, program).unwrap_err();
+                assert_eq!(std::mem::discriminant(&error.reason), std::mem::discriminant(reason));
+                assert!(structural::structural_eq(&error.value, &apply(&f, vec![])));
+                assert!(execution_context.environment.is_none());
+                assert_eq!(execution_context.depth, 0);
+            }
+        };
+        check(&mut execution_context);
+        #[cfg(feature = "parallel")]
+        {
+            execution_context.set_threads(4);
+            check(&mut execution_context);
+        }
+        // Loops inside the function are still exited.
+        let body = sequence(vec![
+            for_range(&i, 1, 3, 1, sequence(vec![assign(&count, dereference(&i)), Value::new(BreakValueInner)])),
+            dereference(&count),
+        ]);
+        let program = sequence(vec![assign(&f, function(vec![], body)), apply(&f, vec![])]);
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(super::equal(&result, &integer(1)));
+    }
+
+    // The cleanup of a finally in the body runs on every iteration, including those left by a continue or break.
+    #[test]
+    fn loop_exits_through_finally() {
+        let (add, equal) = (symbol("add"), symbol("equal"));
+        let mut execution_context = arithmetic_context();
+        let (n, sum, cleanups) = (symbol("n"), symbol("sum"), symbol("cleanups"));
+        let body = Value::new(FinallyValueInner {
+            inner: sequence(vec![
+                when(intrinsic_call(&equal, vec![dereference(&n), integer(3)]), Value::new(ContinueValueInner)),
+                when(intrinsic_call(&equal, vec![dereference(&n), integer(5)]), Value::new(BreakValueInner)),
+                assign(&sum, intrinsic_call(&add, vec![dereference(&sum), dereference(&n)])),
+            ]),
+            cleanup: assign(&cleanups, intrinsic_call(&add, vec![dereference(&cleanups), integer(1)])),
+        });
+        let program = sequence(vec![
+            assign(&sum, integer(0)),
+            assign(&cleanups, integer(0)),
+            Value::new(DoValueInner {
+                variable: n.clone(),
+                elements: tuple((1..=6).map(integer).collect()),
+                body,
+            }),
+        ]);
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(result.is::<NullValueInner>());
+        assert!(super::equal(&execution_context.values[&sum], &integer(1 + 2 + 4)));
+        assert!(super::equal(&execution_context.values[&cleanups], &integer(5)));
+        assert!(execution_context.environment.is_none());
+        assert_eq!(execution_context.depth, 0);
+    }
+
+    // Each iteration binds the variable afresh, so closures created in different iterations see different values.
+    #[test]
+    fn loop_variable_captured_per_iteration() {
+        let equal = symbol("equal");
+        let mut execution_context = arithmetic_context();
+        let (i, first, last) = (symbol("i"), symbol("first"), symbol("last"));
+        let body = sequence(vec![
+            when(
+                intrinsic_call(&equal, vec![dereference(&i), integer(1)]),
+                assign(&first, function(vec![], dereference(&i))),
+            ),
+            assign(&last, function(vec![], dereference(&i))),
+        ]);
+        let program = sequence(vec![for_range(&i, 1, 3, 1, body), tuple(vec![apply(&first, vec![]), apply(&last, vec![])])]);
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(super::equal(&result, &tuple(vec![integer(1), integer(3)])));
+        assert!(!execution_context.values.contains_key(&i));
+    }
+
+    #[test]
+    fn descending_for() {
+        let add = symbol("add");
+        let mut execution_context = arithmetic_context();
+        let (i, sum) = (symbol("i"), symbol("sum"));
+        let add_to_sum = assign(&sum, intrinsic_call(&add, vec![dereference(&sum), dereference(&i)]));
+        // The end is included when a step lands on it, and otherwise the last value before it is.
+        for &(end, expected) in [(1, 10 + 7 + 4 + 1), (2, 10 + 7 + 4), (11, 0)].iter() {
+            let program = sequence(vec![assign(&sum, integer(0)), for_range(&i, 10, end, -3, add_to_sum.clone())]);
+            evaluate(&mut execution_context, program).unwrap();
+            assert!(super::equal(&execution_context.values[&sum], &integer(expected)));
+        }
+        assert!(execution_context.environment.is_none());
+        assert_eq!(execution_context.depth, 0);
+    }
+
+    // The handler runs in the environment, at the depth and with the stack the catch was entered with, whatever the body did
+    // before throwing from inside an application.
+    #[test]
+    fn throw_through_application() {
+        let (add, push, pop) = (symbol("add"), symbol("push"), symbol("pop"));
+        let mut execution_context = arithmetic_context();
+        for (name, function) in [(&push, intrinsic_push as IntrinsicFunction), (&pop, intrinsic_pop)].iter() {
+            Shared::make_mut(&mut execution_context.intrinsics).insert(
+                (*name).clone(),
+                Intrinsic {
+                    function: *function,
+                    effects: Effects::ALL,
+                },
+            );
+        }
+        let (oops, fail, guarded, x, y, e) = (symbol("oops"), symbol("fail"), symbol("guarded"), symbol("x"), symbol("y"), symbol("e"));
+        let (below, top) = (symbol("below"), symbol("top"));
+        execution_context.push(below.clone());
+        execution_context.push(top.clone());
+        let fail_body = sequence(vec![
+            intrinsic_call(&pop, vec![]),
+            intrinsic_call(&push, vec![dereference(&x)]),
+            throw(&oops, intrinsic_call(&add, vec![dereference(&x), integer(1)])),
+        ]);
+        let handler = function(vec![e.clone()], intrinsic_call(&add, vec![dereference(&e), integer(10)]));
+        let guarded_body = tuple(vec![catch(oops.clone(), apply(&fail, vec![dereference(&y)]), handler), dereference(&y)]);
+        let program = sequence(vec![
+            assign(&fail, function(vec![x.clone()], fail_body)),
+            assign(&guarded, function(vec![y.clone()], guarded_body)),
+            apply(&guarded, vec![integer(1)]),
+        ]);
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(super::equal(&result, &tuple(vec![integer(12), integer(1)])));
+        assert_eq!(execution_context.stack.len(), 2);
+        assert!(execution_context.stack[0].ptr_eq(&below) && execution_context.stack[1].ptr_eq(&top));
+        assert!(execution_context.environment.is_none());
+        assert_eq!(execution_context.depth, 0);
+    }
+
+    // Each catch puts back what was below it when it was entered, even when an inner catch already popped and restored part of it.
+    #[test]
+    fn nested_catches_restore_the_stack() {
+        let (push, pop) = (symbol("push"), symbol("pop"));
+        let mut execution_context = arithmetic_context();
+        for (name, function) in [(&push, intrinsic_push as IntrinsicFunction), (&pop, intrinsic_pop)].iter() {
+            Shared::make_mut(&mut execution_context.intrinsics).insert(
+                (*name).clone(),
+                Intrinsic {
+                    function: *function,
+                    effects: Effects::ALL,
+                },
+            );
+        }
+        let (outer, inner, e, i) = (symbol("outer"), symbol("inner"), symbol("e"), symbol("i"));
+        let (a, b, c, x, y) = (symbol("a"), symbol("b"), symbol("c"), symbol("x"), symbol("y"));
+        for value in [&a, &b, &c] {
+            execution_context.push(value.clone());
+        }
+        let pop_twice = || vec![intrinsic_call(&pop, vec![]), intrinsic_call(&pop, vec![])];
+        let push_symbol = |value: &Value| intrinsic_call(&push, vec![value.clone()]);
+        let inner_body = sequence(pop_twice().into_iter().chain(vec![push_symbol(&y), throw(&inner, integer(0))]).collect());
+        // The inner handler sees the stack as it was when the inner catch was entered, and pops its top.
+        let inner_catch = catch(inner.clone(), inner_body, function(vec![e.clone()], intrinsic_call(&pop, vec![])));
+        let outer_body = sequence(pop_twice().into_iter().chain(vec![push_symbol(&x), throw(&outer, inner_catch)]).collect());
+        let program = catch(outer.clone(), outer_body, function(vec![e.clone()], dereference(&e)));
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(result.ptr_eq(&x));
+        assert_eq!(execution_context.stack.len(), 3);
+        assert!(execution_context.stack.iter().zip([&a, &b, &c]).all(|(value, expected)| value.ptr_eq(expected)));
+        // Catches left normally keep nothing recorded for values popped and pushed inside them.
+        let program = for_range(
+            &i,
+            1,
+            1000,
+            1,
+            catch(outer.clone(), sequence(vec![intrinsic_call(&pop, vec![]), push_symbol(&y)]), dereference(&e)),
+        );
+        evaluate(&mut execution_context, program).unwrap();
+        assert!(execution_context.stack[2].ptr_eq(&y));
+        assert!(execution_context.popped.is_empty());
+        assert_eq!(execution_context.stack_floor, 0);
+    }
+
+    #[test]
+    fn finally_rethrows_after_cleanup() {
+        let add = symbol("add");
+        let mut execution_context = arithmetic_context();
+        let (first, second, cleanups, e) = (symbol("first"), symbol("second"), symbol("cleanups"), symbol("e"));
+        let count_cleanup = |inner: Value| {
+            Value::new(FinallyValueInner {
+                inner,
+                cleanup: assign(&cleanups, intrinsic_call(&add, vec![dereference(&cleanups), integer(1)])),
+            })
+        };
+        let catch_all = |inner: Value| catch(Value::new(NullValueInner), inner, function(vec![e.clone()], dereference(&e)));
+        execution_context.assign(cleanups.clone(), integer(0));
+        // Both cleanups run before the throw reaches the catch.
+        let program = catch_all(count_cleanup(count_cleanup(throw(&first, integer(1)))));
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(super::equal(&result, &integer(1)));
+        assert!(super::equal(&execution_context.values[&cleanups], &integer(2)));
+        // A throw from the cleanup replaces the one being rethrown.
+        let program = catch_all(Value::new(FinallyValueInner {
+            inner: throw(&first, integer(1)),
+            cleanup: throw(&second, integer(2)),
+        }));
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(super::equal(&result, &integer(2)));
+        // Without a catch the throw is reported once the cleanup has run.
+        let error = evaluate(&mut execution_context, count_cleanup(throw(&first, integer(3)))).unwrap_err();
+        assert!(matches!(&error.reason, EvaluationErrorReason::UncaughtThrow { tag } if tag == &first));
+        assert!(super::equal(&error.value, &integer(3)));
+        assert!(super::equal(&execution_context.values[&cleanups], &integer(3)));
+        assert_eq!(execution_context.depth, 0);
+    }
+
+    // Exits from parts of a tuple evaluated on other threads continue unwinding on this one.
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn exits_from_parallel_forks() {
+        let (add, equal) = (symbol("add"), symbol("equal"));
+        let mut execution_context = arithmetic_context();
+        execution_context.set_threads(4);
+        let (oops, e, i, count) = (symbol("oops"), symbol("e"), symbol("i"), symbol("count"));
+        let program = catch(
+            oops.clone(),
+            tuple(vec![intrinsic_call(&add, vec![integer(1), integer(2)]), throw(&oops, integer(9))]),
+            function(vec![e.clone()], dereference(&e)),
+        );
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(super::equal(&result, &integer(9)));
+        // A tuple exiting the loop on the given iteration, followed by counting the iteration if it did not.
+        let exit_on = |iteration: i64, exit: Value| {
+            sequence(vec![
+                tuple(vec![
+                    Value::new(IfValueInner {
+                        condition: intrinsic_call(&equal, vec![dereference(&i), integer(iteration)]),
+                        consequent: exit,
+                        alternative: Value::new(NullValueInner),
+                    }),
+                    intrinsic_call(&add, vec![dereference(&i), integer(1)]),
+                ]),
+                assign(&count, intrinsic_call(&add, vec![dereference(&count), integer(1)])),
+            ])
+        };
+        let count_to_five = |body: Value| {
+            Value::new(ForValueInner {
+                variable: i.clone(),
+                start: integer(1),
+                end: integer(5),
+                step: integer(1),
+                body,
+            })
+        };
+        execution_context.assign(count.clone(), integer(0));
+        evaluate(&mut execution_context, count_to_five(exit_on(3, Value::new(BreakValueInner)))).unwrap();
+        assert!(super::equal(&execution_context.values[&count], &integer(2)));
+        execution_context.assign(count.clone(), integer(0));
+        evaluate(&mut execution_context, count_to_five(exit_on(3, Value::new(ContinueValueInner)))).unwrap();
+        assert!(super::equal(&execution_context.values[&count], &integer(4)));
+        assert!(execution_context.environment.is_none());
+        assert_eq!(execution_context.depth, 0);
+    }
+
+    // Held assignments are only visible once released, so releasing values computed at run time keeps the parts sequential.
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn released_assignments_are_not_forked() {
+        let add = symbol("add");
+        let mut execution_context = arithmetic_context();
+        execution_context.set_threads(4);
+        let (x, h) = (symbol("x"), symbol("h"));
+        let increment = Value::new(HoldValueInner {
+            inner: assign(&x, intrinsic_call(&add, vec![dereference(&x), integer(1)])),
+        });
+        let release = || Value::new(ReleaseValueInner { inner: dereference(&h) });
+        let program = sequence(vec![assign(&x, integer(0)), assign(&h, increment), tuple(vec![release(), release()])]);
+        evaluate(&mut execution_context, program).unwrap();
+        assert!(super::equal(&execution_context.values[&x], &integer(2)));
+    }
+
+    // Parts after one that exits stop instead of running on where a sequential evaluation would never have reached.
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn forks_after_an_exit_are_cancelled() {
+        let mut execution_context = arithmetic_context();
+        execution_context.set_threads(4);
+        let (oops, e) = (symbol("oops"), symbol("e"));
+        let forever = Value::new(WhileValueInner {
+            condition: Value::new(BooleanValueInner { inner: true }),
+            body: Value::new(NullValueInner),
+        });
+        let program = catch(
+            oops.clone(),
+            tuple(vec![throw(&oops, integer(1)), forever.clone()]),
+            function(vec![e.clone()], dereference(&e)),
+        );
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(super::equal(&result, &integer(1)));
+        // Errors stop the later parts in the same way.
+        let bad_sum = intrinsic_call(&symbol("add"), vec![integer(1), Value::new(NullValueInner)]);
+        let error = evaluate(&mut execution_context, tuple(vec![integer(0), bad_sum, forever])).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::UnexpectedKind { expected: "Number" }));
+    }
+
+    // A function returned from another keeps the bindings it was created in, and arguments never leak into the globals.
+    #[test]
+    fn closures_capture_their_environment() {
+        let add = symbol("add");
+        let mut execution_context = arithmetic_context();
+        let (make_adder, add_five, x, y) = (symbol("make_adder"), symbol("add_five"), symbol("x"), symbol("y"));
+        let adder = function(vec![x.clone()], intrinsic_call(&add, vec![dereference(&x), dereference(&y)]));
+        let program = sequence(vec![
+            assign(&y, integer(100)),
+            assign(&make_adder, function(vec![y.clone()], adder)),
+            assign(&add_five, apply(&make_adder, vec![integer(5)])),
+            apply(&add_five, vec![integer(1)]),
+        ]);
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(super::equal(&result, &integer(6)));
+        assert!(super::equal(&execution_context.values[&y], &integer(100)));
+        assert!(!execution_context.values.contains_key(&x));
+        // Assigning to an argument changes the binding, not the global of the same name.
+        let (f, n) = (symbol("f"), symbol("n"));
+        let program = sequence(vec![
+            assign(&n, integer(1)),
+            assign(&f, function(vec![n.clone()], sequence(vec![assign(&n, integer(2)), dereference(&n)]))),
+            apply(&f, vec![integer(3)]),
+        ]);
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(super::equal(&result, &integer(2)));
+        assert!(super::equal(&execution_context.values[&n], &integer(1)));
+        assert!(execution_context.environment.is_none());
+    }
+
+    // Each limit stops the evaluation with its own reason, reports the outermost value as far as it got, and leaves the context
+    // ready for the next evaluation.
+    #[test]
+    fn evaluation_budget() {
+        let add = symbol("add");
+        let mut execution_context = arithmetic_context();
+        let (count, f, n) = (symbol("count"), symbol("f"), symbol("n"));
+        let forever = Value::new(WhileValueInner {
+            condition: Value::new(BooleanValueInner { inner: true }),
+            body: assign(&count, intrinsic_call(&add, vec![dereference(&count), integer(1)])),
+        });
+        let program = sequence(vec![assign(&count, integer(0)), forever.clone()]);
+        execution_context.budget.max_steps = Some(1000);
+        let error = evaluate(&mut execution_context, program.clone()).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::StepLimitExceeded));
+        assert!(error.reason.is_limit());
+        assert!(Value::ptr_eq(&error.value, &program));
+        // Assignments made before the limit was hit are kept.
+        let counted = expect_kind::<IntegerValueInner>(&execution_context.values[&count], "Integer")
+            .unwrap()
+            .inner
+            .clone();
+        assert!(counted > BigInt::from(0));
+        // Steps are counted from the start of each evaluation.
+        let result = evaluate(&mut execution_context, intrinsic_call(&add, vec![integer(1), integer(2)])).unwrap();
+        assert!(super::equal(&result, &integer(3)));
+        execution_context.budget.max_steps = None;
+
+        execution_context.budget.max_depth = Some(50);
+        let recursive = function(vec![n.clone()], intrinsic_call(&add, vec![integer(1), apply(&f, vec![dereference(&n)])]));
+        evaluate(&mut execution_context, assign(&f, recursive)).unwrap();
+        let error = evaluate(&mut execution_context, apply(&f, vec![integer(0)])).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::DepthLimitExceeded));
+        assert!(execution_context.environment.is_none());
+        assert_eq!(execution_context.depth, 0);
+        execution_context.budget.max_depth = None;
+
+        execution_context.budget.deadline = Some(Instant::now());
+        let error = evaluate(&mut execution_context, forever.clone()).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::DeadlineExceeded));
+        execution_context.budget.deadline = None;
+
+        // An interrupt can come from another thread while the evaluation runs.
+        let interrupted = execution_context.interrupted.clone();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            interrupted.store(true, Ordering::SeqCst);
+        });
+        let error = evaluate(&mut execution_context, forever).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::Interrupted));
+        assert_eq!(error.to_string(), "the evaluation was interrupted");
+        interrupter.join().unwrap();
+        // The interrupt is consumed by the evaluation it stopped.
+        let result = evaluate(&mut execution_context, intrinsic_call(&add, vec![integer(1), integer(2)])).unwrap();
+        assert!(super::equal(&result, &integer(3)));
+    }
+
+    struct Recorder {
+        events: Shared<Lock<Vec<String>>>,
+        steps: Shared<Lock<usize>>,
+    }
+
+    fn name(symbol: &Value) -> String {
+        symbol.downcast::<SymbolValueInner>().name.clone()
+    }
+
+    fn show(value: &Value) -> String {
+        match expect_kind::<IntegerValueInner>(value, "Integer") {
+            Ok(integer) => integer.inner.to_string(),
+            Err(_) => "?".to_owned(),
+        }
+    }
+
+    impl EvaluationObserver for Recorder {
+        fn on_step(&mut self, _execution_context: &ExecutionContext, _input: &Value, _output: &Value) {
+            *self.steps.borrow_mut() += 1;
+        }
+
+        fn on_dereference(&mut self, _execution_context: &ExecutionContext, symbol: &Value, value: &Value) {
+            self.events.borrow_mut().push(format!("dereference {} {}", name(symbol), show(value)));
+        }
+
+        fn on_intrinsic_call_start(&mut self, _execution_context: &ExecutionContext, intrinsic: &Value, arguments: &[Value]) {
+            self.events.borrow_mut().push(format!("start {} {}", name(intrinsic), arguments.len()));
+        }
+
+        fn on_intrinsic_call(&mut self, _execution_context: &ExecutionContext, intrinsic: &Value, _arguments: &[Value], result: &Value) {
+            self.events.borrow_mut().push(format!("call {} {}", name(intrinsic), show(result)));
+        }
+
+        fn on_assignment(&mut self, execution_context: &ExecutionContext, target: &Value, value: &Value) {
+            // The assignment has already been made when observers hear of it.
+            assert!(Value::ptr_eq(&execution_context.values[target], value));
+            self.events.borrow_mut().push(format!("assign {} {}", name(target), show(value)));
+        }
+
+        fn on_push(&mut self, execution_context: &ExecutionContext, value: &Value) {
+            self.events.borrow_mut().push(format!("push {} {}", show(value), execution_context.stack.len()));
+        }
+
+        fn on_pop(&mut self, execution_context: &ExecutionContext, value: &Value) {
+            self.events.borrow_mut().push(format!("pop {} {}", show(value), execution_context.stack.len()));
+        }
+    }
+
+    // Observers hear of every step, lookup, intrinsic call, assignment and stack change, in the order they happen.
+    #[test]
+    fn observers_see_evaluation_events() {
+        let (add, push, pop, x) = (symbol("add"), symbol("push"), symbol("pop"), symbol("x"));
+        let mut execution_context = arithmetic_context();
+        for (name, function) in [(&push, intrinsic_push as IntrinsicFunction), (&pop, intrinsic_pop)].iter() {
+            Shared::make_mut(&mut execution_context.intrinsics).insert(
+                (*name).clone(),
+                Intrinsic {
+                    function: *function,
+                    effects: Effects::ALL,
+                },
+            );
+        }
+        let events = Shared::new(Lock::new(Vec::new()));
+        let steps = Shared::new(Lock::new(0));
+        execution_context.observers.push(Box::new(Recorder {
+            events: events.clone(),
+            steps: steps.clone(),
+        }));
+        let program = sequence(vec![
+            assign(&x, intrinsic_call(&add, vec![integer(1), integer(2)])),
+            intrinsic_call(&push, vec![dereference(&x)]),
+            intrinsic_call(&pop, vec![]),
+        ]);
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(super::equal(&result, &integer(3)));
+        let expected = [
+            "start add 2",
+            "call add 3",
+            "assign x 3",
+            "dereference x 3",
+            "start push 1",
+            "push 3 1",
+            "call push ?",
+            "start pop 0",
+            "pop 3 0",
+            "call pop 3",
+        ];
+        assert_eq!(*events.borrow(), expected);
+        assert!(*steps.borrow() > 0);
+    }
+
+    // With the sync feature a context, with the closures and values it holds, can carry on evaluating on another thread, and the
+    // same value can be read from several threads at once.
+    #[cfg(feature = "sync")]
+    #[test]
+    fn contexts_move_between_threads() {
+        let add = symbol("add");
+        let mut execution_context = arithmetic_context();
+        let (f, x, total) = (symbol("f"), symbol("x"), symbol("total"));
+        let program = sequence(vec![
+            assign(&total, integer(1)),
+            assign(&f, function(vec![x.clone()], intrinsic_call(&add, vec![dereference(&x), dereference(&total)]))),
+        ]);
+        evaluate(&mut execution_context, program).unwrap();
+        let call = apply(&f, vec![integer(2)]);
+        let (mut execution_context, result) = std::thread::spawn(move || {
+            let result = evaluate(&mut execution_context, call).unwrap();
+            (execution_context, result)
+        })
+        .join()
+        .unwrap();
+        assert!(super::equal(&result, &integer(3)));
+        let result = evaluate(&mut execution_context, apply(&f, vec![integer(5)])).unwrap();
+        assert!(super::equal(&result, &integer(6)));
+        let shared = tuple(vec![integer(1), symbol("y")]);
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                std::thread::spawn(move || structural::structural_hash(&shared))
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), structural::structural_hash(&shared));
         }
-    } else {
-        value
     }
 }
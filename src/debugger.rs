@@ -0,0 +1,365 @@
+use crate::{data::*, serialization, value::MaybeSend, Value};
+use std::{
+    io::{self, BufRead, Write},
+    sync::atomic::Ordering,
+};
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum Breakpoint {
+    Dereference(String),
+    Intrinsic(String),
+    Assignment(String),
+}
+
+pub enum DebuggerEvent {
+    Step { value: Value },
+    Dereference { symbol: Value, value: Value },
+    IntrinsicCall { intrinsic: Value, arguments: Vec<Value> },
+    Assignment { target: Value, value: Value },
+}
+
+#[derive(Clone, Copy)]
+pub enum DebuggerCommand {
+    Step,
+    StepOver,
+    StepOut,
+    Continue,
+}
+
+pub trait DebuggerFrontend: MaybeSend {
+    fn pause(&mut self, execution_context: &ExecutionContext, event: &DebuggerEvent, breakpoints: &mut Vec<Breakpoint>) -> DebuggerCommand;
+}
+
+impl<F: FnMut(&ExecutionContext, &DebuggerEvent, &mut Vec<Breakpoint>) -> DebuggerCommand + MaybeSend> DebuggerFrontend for F {
+    fn pause(&mut self, execution_context: &ExecutionContext, event: &DebuggerEvent, breakpoints: &mut Vec<Breakpoint>) -> DebuggerCommand {
+        self(execution_context, event, breakpoints)
+    }
+}
+
+enum Mode {
+    Step,
+    StepOver(usize),
+    StepOut(usize),
+    Continue,
+}
+
+pub struct Debugger<F: DebuggerFrontend> {
+    pub frontend: F,
+    pub breakpoints: Vec<Breakpoint>,
+    mode: Mode,
+}
+
+fn symbol_name(value: &Value) -> String {
+    value
+        .try_downcast::<SymbolValueInner>()
+        .map(|symbol| symbol.name.clone())
+        .unwrap_or_else(|| "?".to_owned())
+}
+
+impl<F: DebuggerFrontend> Debugger<F> {
+    pub fn new(frontend: F, command: DebuggerCommand) -> Self {
+        let mut debugger = Debugger {
+            frontend,
+            breakpoints: Vec::new(),
+            mode: Mode::Continue,
+        };
+        debugger.set_mode(command, 0);
+        debugger
+    }
+
+    fn set_mode(&mut self, command: DebuggerCommand, depth: usize) {
+        self.mode = match command {
+            DebuggerCommand::Step => Mode::Step,
+            DebuggerCommand::StepOver => Mode::StepOver(depth),
+            DebuggerCommand::StepOut => Mode::StepOut(depth),
+            DebuggerCommand::Continue => Mode::Continue,
+        };
+    }
+
+    fn pause(&mut self, execution_context: &ExecutionContext, event: DebuggerEvent) {
+        let command = self.frontend.pause(execution_context, &event, &mut self.breakpoints);
+        self.set_mode(command, execution_context.depth);
+    }
+
+    fn is_breakpoint(&self, breakpoint: fn(String) -> Breakpoint, symbol: &Value) -> bool {
+        match symbol.try_downcast::<SymbolValueInner>() {
+            Some(symbol) => self.breakpoints.contains(&breakpoint(symbol.name.clone())),
+            None => false,
+        }
+    }
+}
+
+impl<F: DebuggerFrontend> EvaluationObserver for Debugger<F> {
+    fn on_step_start(&mut self, execution_context: &ExecutionContext, value: &Value) {
+        let should_pause = match self.mode {
+            Mode::Step => true,
+            Mode::StepOver(depth) => execution_context.depth <= depth,
+            Mode::StepOut(depth) => execution_context.depth < depth,
+            Mode::Continue => false,
+        };
+        if should_pause {
+            self.pause(execution_context, DebuggerEvent::Step { value: value.clone() });
+        }
+    }
+
+    fn on_dereference(&mut self, execution_context: &ExecutionContext, symbol: &Value, value: &Value) {
+        if self.is_breakpoint(Breakpoint::Dereference, symbol) {
+            self.pause(
+                execution_context,
+                DebuggerEvent::Dereference {
+                    symbol: symbol.clone(),
+                    value: value.clone(),
+                },
+            );
+        }
+    }
+
+    fn on_intrinsic_call_start(&mut self, execution_context: &ExecutionContext, intrinsic: &Value, arguments: &[Value]) {
+        if self.is_breakpoint(Breakpoint::Intrinsic, intrinsic) {
+            self.pause(
+                execution_context,
+                DebuggerEvent::IntrinsicCall {
+                    intrinsic: intrinsic.clone(),
+                    arguments: arguments.to_vec(),
+                },
+            );
+        }
+    }
+
+    fn on_assignment(&mut self, execution_context: &ExecutionContext, target: &Value, value: &Value) {
+        if self.is_breakpoint(Breakpoint::Assignment, target) {
+            self.pause(
+                execution_context,
+                DebuggerEvent::Assignment {
+                    target: target.clone(),
+                    value: value.clone(),
+                },
+            );
+        }
+    }
+}
+
+pub struct TerminalFrontend;
+
+impl TerminalFrontend {
+    fn print_event(event: &DebuggerEvent) {
+        match event {
+            DebuggerEvent::Step { value } => {
+                println!("step:");
+                println!("{}", serialization::serialize_readable(value.clone()));
+            }
+            DebuggerEvent::Dereference { symbol, value } => {
+                println!("dereference of {}:", symbol_name(symbol));
+                println!("{}", serialization::serialize_readable(value.clone()));
+            }
+            DebuggerEvent::IntrinsicCall { intrinsic, arguments } => {
+                println!("call of {}:", symbol_name(intrinsic));
+                for argument in arguments {
+                    println!("{}", serialization::serialize_readable(argument.clone()));
+                }
+            }
+            DebuggerEvent::Assignment { target, value } => {
+                println!("assignment to {}:", symbol_name(target));
+                println!("{}", serialization::serialize_readable(value.clone()));
+            }
+        }
+    }
+
+    fn print_help() {
+        println!("step (s)                      evaluate until the next step");
+        println!("next (n)                      evaluate until the next step at the same or a shallower depth");
+        println!("out (o)                       evaluate until the next step at a shallower depth");
+        println!("continue (c)                  evaluate until the next breakpoint");
+        println!("print (p)                     print the current event");
+        println!("values (v)                    print the global values");
+        println!("environment (e)               print the local environments");
+        println!("stack (k)                     print the stack");
+        println!("break (b) d|i|a <symbol>      break on a dereference, an intrinsic call or an assignment");
+        println!("delete (d) <index>            delete a breakpoint");
+        println!("breakpoints (l)               list the breakpoints");
+        println!("quit (q)                      interrupt the evaluation");
+    }
+}
+
+impl DebuggerFrontend for TerminalFrontend {
+    fn pause(&mut self, execution_context: &ExecutionContext, event: &DebuggerEvent, breakpoints: &mut Vec<Breakpoint>) -> DebuggerCommand {
+        TerminalFrontend::print_event(event);
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            print!("(depth {}) ", execution_context.depth);
+            io::stdout().flush().unwrap();
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => return DebuggerCommand::Continue,
+            };
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            match words.as_slice() {
+                ["step"] | ["s"] => return DebuggerCommand::Step,
+                ["next"] | ["n"] => return DebuggerCommand::StepOver,
+                ["out"] | ["o"] => return DebuggerCommand::StepOut,
+                ["continue"] | ["c"] => return DebuggerCommand::Continue,
+                ["print"] | ["p"] => TerminalFrontend::print_event(event),
+                ["values"] | ["v"] => {
+                    for (symbol, value) in execution_context.values.iter() {
+                        println!("{} =", symbol_name(symbol));
+                        println!("{}", serialization::serialize_readable(value.clone()));
+                    }
+                }
+                ["environment"] | ["e"] => {
+                    let mut environment = execution_context.environment.clone();
+                    let mut index = 0;
+                    while let Some(current) = environment {
+                        // Values may be closures over this same environment, which is locked again to serialize them.
+                        let (values, parent) = {
+                            let current = current.borrow();
+                            (current.values.clone(), current.parent.clone())
+                        };
+                        for (symbol, value) in values {
+                            println!("#{} {} =", index, symbol_name(&symbol));
+                            println!("{}", serialization::serialize_readable(value));
+                        }
+                        environment = parent;
+                        index += 1;
+                    }
+                }
+                ["stack"] | ["k"] => {
+                    for (index, value) in execution_context.stack.iter().enumerate().rev() {
+                        println!("#{}", index);
+                        println!("{}", serialization::serialize_readable(value.clone()));
+                    }
+                }
+                ["break", kind, name] | ["b", kind, name] => {
+                    let breakpoint = match *kind {
+                        "dereference" | "d" => Breakpoint::Dereference((*name).to_owned()),
+                        "intrinsic" | "i" => Breakpoint::Intrinsic((*name).to_owned()),
+                        "assignment" | "a" => Breakpoint::Assignment((*name).to_owned()),
+                        _ => {
+                            println!("unknown breakpoint kind {}", kind);
+                            continue;
+                        }
+                    };
+                    breakpoints.push(breakpoint);
+                }
+                ["delete", index] | ["d", index] => match index.parse::<usize>() {
+                    Ok(index) if index < breakpoints.len() => {
+                        breakpoints.remove(index);
+                    }
+                    _ => println!("no breakpoint {}", index),
+                },
+                ["breakpoints"] | ["l"] => {
+                    for (index, breakpoint) in breakpoints.iter().enumerate() {
+                        match breakpoint {
+                            Breakpoint::Dereference(name) => println!("#{} dereference {}", index, name),
+                            Breakpoint::Intrinsic(name) => println!("#{} intrinsic {}", index, name),
+                            Breakpoint::Assignment(name) => println!("#{} assignment {}", index, name),
+                        }
+                    }
+                }
+                ["quit"] | ["q"] => {
+                    breakpoints.clear();
+                    execution_context.interrupted.store(true, Ordering::SeqCst);
+                    return DebuggerCommand::Continue;
+                }
+                _ => TerminalFrontend::print_help(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::tests::*,
+        value::{Lock, Shared},
+    };
+
+    fn describe(execution_context: &ExecutionContext, event: &DebuggerEvent) -> String {
+        match event {
+            DebuggerEvent::Step { .. } => format!("step {}", execution_context.depth),
+            DebuggerEvent::Dereference { symbol, .. } => format!("dereference {}", symbol_name(symbol)),
+            DebuggerEvent::IntrinsicCall { intrinsic, arguments } => format!("call {} {}", symbol_name(intrinsic), arguments.len()),
+            DebuggerEvent::Assignment { target, .. } => format!("assign {}", symbol_name(target)),
+        }
+    }
+
+    // Runs the program under a debugger whose frontend answers with the given commands in turn, then continues, and returns
+    // the events it paused on.
+    fn debug(program: Value, start: DebuggerCommand, breakpoints: Vec<Breakpoint>, commands: Vec<DebuggerCommand>) -> Vec<String> {
+        let mut execution_context = arithmetic_context();
+        let events = Shared::new(Lock::new(Vec::new()));
+        let recorded = events.clone();
+        let mut commands = commands.into_iter();
+        let frontend = move |execution_context: &ExecutionContext, event: &DebuggerEvent, _breakpoints: &mut Vec<Breakpoint>| {
+            recorded.borrow_mut().push(describe(execution_context, event));
+            commands.next().unwrap_or(DebuggerCommand::Continue)
+        };
+        let mut debugger = Debugger::new(frontend, start);
+        debugger.breakpoints = breakpoints;
+        execution_context.observers.push(Box::new(debugger));
+        evaluate(&mut execution_context, program).unwrap();
+        let events = events.borrow().clone();
+        events
+    }
+
+    fn program() -> Value {
+        let (add, x) = (symbol("add"), symbol("x"));
+        sequence(vec![
+            assign(&x, intrinsic_call(&add, vec![integer(1), integer(2)])),
+            intrinsic_call(&add, vec![dereference(&x), integer(3)]),
+        ])
+    }
+
+    #[test]
+    fn breakpoints() {
+        let breakpoints = vec![
+            Breakpoint::Intrinsic("add".to_owned()),
+            Breakpoint::Assignment("x".to_owned()),
+            Breakpoint::Dereference("x".to_owned()),
+        ];
+        let events = debug(program(), DebuggerCommand::Continue, breakpoints, vec![]);
+        assert_eq!(events, ["call add 2", "assign x", "dereference x", "call add 2"]);
+        // Breakpoints on other names never pause.
+        let events = debug(program(), DebuggerCommand::Continue, vec![Breakpoint::Assignment("y".to_owned())], vec![]);
+        assert!(events.is_empty());
+    }
+
+    // The frontend can change the breakpoints while paused.
+    #[test]
+    fn frontend_removes_breakpoints() {
+        let mut execution_context = arithmetic_context();
+        let pauses = Shared::new(Lock::new(0));
+        let counted = pauses.clone();
+        let frontend = move |_execution_context: &ExecutionContext, _event: &DebuggerEvent, breakpoints: &mut Vec<Breakpoint>| {
+            *counted.borrow_mut() += 1;
+            breakpoints.clear();
+            DebuggerCommand::Continue
+        };
+        let mut debugger = Debugger::new(frontend, DebuggerCommand::Continue);
+        debugger.breakpoints.push(Breakpoint::Intrinsic("add".to_owned()));
+        execution_context.observers.push(Box::new(debugger));
+        evaluate(&mut execution_context, program()).unwrap();
+        assert_eq!(*pauses.borrow(), 1);
+    }
+
+    #[test]
+    fn stepping() {
+        let all_steps = debug(program(), DebuggerCommand::Step, vec![], vec![DebuggerCommand::Step; 1000]);
+        assert_eq!(all_steps.len(), 17);
+        assert!(all_steps.iter().all(|event| event.starts_with("step")));
+        // Stepping over the assignment pauses next on the second element of the sequence, skipping everything deeper.
+        let over = debug(program(), DebuggerCommand::Step, vec![], vec![DebuggerCommand::Step, DebuggerCommand::StepOver]);
+        assert_eq!(over, ["step 1", "step 2", "step 2"]);
+        let out = debug(
+            program(),
+            DebuggerCommand::Step,
+            vec![],
+            vec![DebuggerCommand::Step, DebuggerCommand::Step, DebuggerCommand::StepOut],
+        );
+        assert_eq!(out, ["step 1", "step 2", "step 3", "step 2"]);
+        // Continuing still pauses on breakpoints.
+        let events = debug(program(), DebuggerCommand::Step, vec![Breakpoint::Assignment("x".to_owned())], vec![]);
+        assert_eq!(events, ["step 1", "assign x"]);
+    }
+}
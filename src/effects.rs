@@ -0,0 +1,295 @@
+#[cfg(feature = "parallel")]
+use crate::value::{Lock, Shared, Weak, WeakValue};
+use crate::{data::*, Value};
+#[cfg(feature = "parallel")]
+use std::collections::HashMap;
+use std::{
+    collections::HashSet,
+    ops::{BitOr, BitOrAssign},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Effects {
+    pub reads_state: bool,
+    pub writes_state: bool,
+    pub io: bool,
+}
+
+#[allow(dead_code)]
+impl Effects {
+    pub const PURE: Effects = Effects {
+        reads_state: false,
+        writes_state: false,
+        io: false,
+    };
+
+    pub const READS_STATE: Effects = Effects {
+        reads_state: true,
+        ..Effects::PURE
+    };
+
+    pub const WRITES_STATE: Effects = Effects {
+        writes_state: true,
+        ..Effects::PURE
+    };
+
+    pub const IO: Effects = Effects { io: true, ..Effects::PURE };
+
+    pub const ALL: Effects = Effects {
+        reads_state: true,
+        writes_state: true,
+        io: true,
+    };
+
+    pub fn is_pure(self) -> bool {
+        self == Effects::PURE
+    }
+}
+
+impl BitOr for Effects {
+    type Output = Effects;
+
+    fn bitor(self, other: Effects) -> Effects {
+        Effects {
+            reads_state: self.reads_state || other.reads_state,
+            writes_state: self.writes_state || other.writes_state,
+            io: self.io || other.io,
+        }
+    }
+}
+
+impl BitOrAssign for Effects {
+    fn bitor_assign(&mut self, other: Effects) {
+        *self = *self | other;
+    }
+}
+
+// What evaluating a value may do, together with the variables it may read, or `None` if they cannot be determined statically.
+pub struct Analysis {
+    pub effects: Effects,
+    pub dependencies: Option<HashSet<Value>>,
+}
+
+// An over-approximation of what evaluating the value may do. Functions applied through a variable are resolved with the current
+// value of that variable, unless the variable is bound as an argument, a loop variable or in a closure anywhere in the tree, in which case
+// nothing is known about the function. Held values are analyzed as well, since they may be released.
+pub fn analyze(execution_context: &ExecutionContext, value: &Value) -> Analysis {
+    let mut effects = Effects::PURE;
+    let mut dependencies = Some(HashSet::new());
+    let mut visited = HashSet::new();
+    let mut bound = HashSet::new();
+    let mut applied = HashSet::new();
+    let mut pending = vec![value.clone()];
+    loop {
+        while let Some(value) = pending.pop() {
+            if !visited.insert(value.clone()) {
+                continue;
+            }
+            if value.is::<AssignmentValueInner>() {
+                effects |= Effects::WRITES_STATE;
+            } else if value.is::<ShiftValueInner>() || value.is::<ContinuationValueInner>() {
+                // Shifting or resuming a continuation runs an evaluation context that is not part of the tree.
+                effects |= Effects::ALL;
+                dependencies = None;
+            } else if let Some(value_inner) = value.try_downcast::<ReleaseValueInner>() {
+                // Only a literal held value is part of the tree; anything else is released from wherever it was computed.
+                if !value_inner.inner.is::<HoldValueInner>() {
+                    effects |= Effects::ALL;
+                    dependencies = None;
+                }
+            } else if let Some(value_inner) = value.try_downcast::<DereferenceValueInner>() {
+                effects |= Effects::READS_STATE;
+                match &mut dependencies {
+                    Some(dependencies) if value_inner.inner.is::<SymbolValueInner>() => {
+                        dependencies.insert(value_inner.inner.clone());
+                    }
+                    _ => dependencies = None,
+                }
+            } else if let Some(value_inner) = value.try_downcast::<IntrinsicCallValueInner>() {
+                match execution_context.intrinsics.get(&value_inner.intrinsic) {
+                    Some(intrinsic) => {
+                        effects |= intrinsic.effects;
+                        // The state an intrinsic reads, like the stack, is not a variable that can be depended on.
+                        if intrinsic.effects.reads_state {
+                            dependencies = None;
+                        }
+                    }
+                    None => {
+                        effects |= Effects::ALL;
+                        dependencies = None;
+                    }
+                }
+            } else if let Some(value_inner) = value.try_downcast::<FunctionApplicationValueInner>() {
+                let function = &value_inner.function;
+                if let Some(function_inner) = function.try_downcast::<DereferenceValueInner>() {
+                    if function_inner.inner.is::<SymbolValueInner>() {
+                        applied.insert(function_inner.inner.clone());
+                    } else {
+                        effects |= Effects::ALL;
+                        dependencies = None;
+                    }
+                } else if !function.is::<ExecutableFunctionValueInner>() && !function.is::<ClosureValueInner>() {
+                    effects |= Effects::ALL;
+                    dependencies = None;
+                }
+            } else if let Some(value_inner) = value.try_downcast::<ForValueInner>() {
+                bound.insert(value_inner.variable.clone());
+            } else if let Some(value_inner) = value.try_downcast::<DoValueInner>() {
+                bound.insert(value_inner.variable.clone());
+            } else if let Some(value_inner) = value.try_downcast::<ExecutableFunctionValueInner>() {
+                bound.extend(get_parts(value_inner.arguments.clone()));
+            } else if let Some(value_inner) = value.try_downcast::<ClosureValueInner>() {
+                bound.extend(get_parts(value_inner.arguments.clone()));
+                let mut environment = value_inner.environment.clone();
+                while let Some(current) = environment {
+                    let current = current.borrow();
+                    bound.extend(current.values.keys().cloned());
+                    environment = current.parent.clone();
+                }
+            }
+            pending.extend(get_parts(value));
+        }
+        for symbol in &applied {
+            if let Some(function) = execution_context.lookup(symbol) {
+                if !visited.contains(&function) {
+                    pending.push(function);
+                }
+            }
+        }
+        if pending.is_empty() {
+            break;
+        }
+    }
+    if applied.iter().any(|symbol| bound.contains(symbol)) {
+        effects |= Effects::ALL;
+        dependencies = None;
+    }
+    Analysis { effects, dependencies }
+}
+
+#[allow(dead_code)]
+pub fn infer_effects(execution_context: &ExecutionContext, value: &Value) -> Effects {
+    analyze(execution_context, value).effects
+}
+
+// Effects of the values evaluated as parts of tuples, so that nested tuples do not walk their parts again on every step. An entry
+// is only used in the environment it was inferred in, and the whole cache is cleared whenever a variable is assigned, since that
+// changes which functions are applied through variables. Values and environments are held weakly, which keeps their addresses
+// from being reused while an entry exists, and dead entries are pruned whenever the cache has doubled in size.
+#[cfg(feature = "parallel")]
+#[derive(Default)]
+pub struct EffectsCache {
+    entries: HashMap<usize, CachedEffects>,
+    pruned_length: usize,
+}
+
+#[cfg(feature = "parallel")]
+struct CachedEffects {
+    value: WeakValue,
+    environment: Option<Weak<Lock<Environment>>>,
+    effects: Effects,
+}
+
+#[cfg(feature = "parallel")]
+impl EffectsCache {
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(feature = "parallel")]
+pub fn infer_effects_cached(execution_context: &mut ExecutionContext, value: &Value) -> Effects {
+    let in_environment = |cached: &CachedEffects| match (&cached.environment, &execution_context.environment) {
+        (Some(cached), Some(environment)) => Weak::as_ptr(cached) == Shared::as_ptr(environment),
+        (None, None) => true,
+        _ => false,
+    };
+    if let Some(cached) = execution_context.effects_cache.entries.get(&value.address()) {
+        if in_environment(cached) {
+            return cached.effects;
+        }
+    }
+    let effects = infer_effects(execution_context, value);
+    let cache = &mut execution_context.effects_cache;
+    if cache.entries.len() >= 2 * cache.pruned_length {
+        cache
+            .entries
+            .retain(|_, cached| !cached.value.is_dead() && !matches!(&cached.environment, Some(environment) if environment.strong_count() == 0));
+        cache.pruned_length = cache.entries.len().max(16);
+    }
+    cache.entries.insert(
+        value.address(),
+        CachedEffects {
+            value: value.downgrade(),
+            environment: execution_context.environment.as_ref().map(Shared::downgrade),
+            effects,
+        },
+    );
+    effects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::tests::*;
+    use std::collections::HashMap;
+
+    fn pure_function_context(f: &Value) -> ExecutionContext {
+        let x = symbol("x");
+        let mut execution_context = arithmetic_context();
+        execution_context.assign(f.clone(), function(vec![x.clone()], dereference(&x)));
+        execution_context
+    }
+
+    #[test]
+    fn applications_through_variables() {
+        let (f, y) = (symbol("f"), symbol("y"));
+        let execution_context = pure_function_context(&f);
+        let analysis = analyze(&execution_context, &apply(&f, vec![dereference(&y)]));
+        assert_eq!(analysis.effects, Effects::READS_STATE);
+        let dependencies = analysis.dependencies.unwrap();
+        assert!(dependencies.contains(&y) && dependencies.contains(&f));
+        let analysis = analyze(&execution_context, &assign(&y, integer(1)));
+        assert_eq!(analysis.effects, Effects::WRITES_STATE);
+        // Once `f` is an argument, the function applied through it is unknown.
+        let analysis = analyze(&execution_context, &function(vec![f.clone()], apply(&f, vec![])));
+        assert_eq!(analysis.effects, Effects::ALL);
+        assert!(analysis.dependencies.is_none());
+    }
+
+    #[test]
+    fn released_values() {
+        let (h, y) = (symbol("h"), symbol("y"));
+        let execution_context = ExecutionContext::new(HashMap::new());
+        let literal = Value::new(ReleaseValueInner {
+            inner: Value::new(HoldValueInner { inner: dereference(&y) }),
+        });
+        let analysis = analyze(&execution_context, &literal);
+        assert_eq!(analysis.effects, Effects::READS_STATE);
+        assert!(analysis.dependencies.unwrap().contains(&y));
+        // The held value behind a variable may do anything, including assigning.
+        let released = Value::new(ReleaseValueInner { inner: dereference(&h) });
+        let analysis = analyze(&execution_context, &released);
+        assert_eq!(analysis.effects, Effects::ALL);
+        assert!(analysis.dependencies.is_none());
+    }
+
+    #[test]
+    fn loop_variables_are_bound() {
+        let f = symbol("f");
+        let execution_context = pure_function_context(&f);
+        let loops = [
+            for_range(&f, 1, 2, 1, apply(&f, vec![])),
+            Value::new(DoValueInner {
+                variable: f.clone(),
+                elements: tuple(vec![]),
+                body: apply(&f, vec![]),
+            }),
+        ];
+        for value in &loops {
+            let analysis = analyze(&execution_context, value);
+            assert_eq!(analysis.effects, Effects::ALL);
+            assert!(analysis.dependencies.is_none());
+        }
+    }
+}
@@ -1,114 +1,14 @@
-use crate::{data::*, Value};
+use crate::{
+    data::*,
+    value_kind,
+    value_kind::{ComponentsLayout, Rendering},
+    Value,
+};
 use cairo::{Content, Context, Format, ImageSurface, Pattern, RecordingSurface, SurfacePattern};
 use gio::prelude::*;
 use gtk::{prelude::*, Application, ApplicationWindow, DrawingArea};
-use itertools::Itertools;
 use std::{cell::RefCell, rc::Rc};
 
-fn get_parts(value: Value) -> Vec<Value> {
-    if let Some(value_inner) = value.try_downcast::<HoldValueInner>() {
-        vec![value_inner.inner.clone()]
-    } else if let Some(value_inner) = value.try_downcast::<ReleaseValueInner>() {
-        vec![value_inner.inner.clone()]
-    } else if let Some(value_inner) = value.try_downcast::<AssignmentValueInner>() {
-        vec![value_inner.source.clone(), value_inner.target.clone()]
-    } else if let Some(value_inner) = value.try_downcast::<DereferenceValueInner>() {
-        vec![value_inner.inner.clone()]
-    } else if let Some(value_inner) = value.try_downcast::<ExecutableSequenceValueInner>() {
-        value_inner.inner.clone()
-    } else if let Some(value_inner) = value.try_downcast::<ExecutableFunctionValueInner>() {
-        vec![value_inner.arguments.clone(), value_inner.body.clone()]
-    } else if let Some(value_inner) = value.try_downcast::<FunctionApplicationValueInner>() {
-        vec![value_inner.function.clone(), value_inner.arguments.clone()]
-    } else if let Some(value_inner) = value.try_downcast::<IntrinsicCallValueInner>() {
-        vec![value_inner.intrinsic.clone(), value_inner.arguments.clone()]
-    } else if let Some(value_inner) = value.try_downcast::<TupleValueInner>() {
-        value_inner.inner.clone()
-    } else {
-        vec![]
-    }
-}
-
-fn replace_parts(value: Value, parts: &[Value]) -> Value {
-    if let Some(value_inner) = value.try_downcast::<HoldValueInner>() {
-        assert_eq!(parts.len(), 1);
-        let inner = parts[0].clone();
-        if inner == value_inner.inner {
-            value
-        } else {
-            Value::new(HoldValueInner { inner })
-        }
-    } else if let Some(value_inner) = value.try_downcast::<ReleaseValueInner>() {
-        assert_eq!(parts.len(), 1);
-        let inner = parts[0].clone();
-        if inner == value_inner.inner {
-            value
-        } else {
-            Value::new(ReleaseValueInner { inner })
-        }
-    } else if let Some(value_inner) = value.try_downcast::<AssignmentValueInner>() {
-        assert_eq!(parts.len(), 2);
-        let source = parts[0].clone();
-        let target = parts[1].clone();
-        if source == value_inner.source && target == value_inner.target {
-            value
-        } else {
-            Value::new(AssignmentValueInner { source, target })
-        }
-    } else if let Some(value_inner) = value.try_downcast::<DereferenceValueInner>() {
-        assert_eq!(parts.len(), 1);
-        let inner = parts[0].clone();
-        if inner == value_inner.inner {
-            value
-        } else {
-            Value::new(DereferenceValueInner { inner })
-        }
-    } else if let Some(value_inner) = value.try_downcast::<ExecutableSequenceValueInner>() {
-        let inner = parts.to_vec();
-        if inner == value_inner.inner {
-            value
-        } else {
-            Value::new(ExecutableSequenceValueInner { inner })
-        }
-    } else if let Some(value_inner) = value.try_downcast::<ExecutableFunctionValueInner>() {
-        assert_eq!(parts.len(), 2);
-        let arguments = parts[0].clone();
-        let body = parts[1].clone();
-        if arguments == value_inner.arguments && body == value_inner.body {
-            value
-        } else {
-            Value::new(ExecutableFunctionValueInner { arguments, body })
-        }
-    } else if let Some(value_inner) = value.try_downcast::<FunctionApplicationValueInner>() {
-        assert_eq!(parts.len(), 2);
-        let function = parts[0].clone();
-        let arguments = parts[1].clone();
-        if function == value_inner.function && arguments == value_inner.arguments {
-            value
-        } else {
-            Value::new(FunctionApplicationValueInner { function, arguments })
-        }
-    } else if let Some(value_inner) = value.try_downcast::<IntrinsicCallValueInner>() {
-        assert_eq!(parts.len(), 2);
-        let intrinsic = parts[0].clone();
-        let arguments = parts[1].clone();
-        if intrinsic == value_inner.intrinsic && arguments == value_inner.arguments {
-            value
-        } else {
-            Value::new(IntrinsicCallValueInner { intrinsic, arguments })
-        }
-    } else if let Some(value_inner) = value.try_downcast::<TupleValueInner>() {
-        let inner = parts.to_vec();
-        if inner == value_inner.inner {
-            value
-        } else {
-            Value::new(TupleValueInner { inner })
-        }
-    } else {
-        unreachable!()
-    }
-}
-
 fn get_part(value: Value, path: &[usize]) -> Option<Value> {
     if path.is_empty() {
         Some(value)
@@ -224,16 +124,6 @@ fn render_rasterized(render_result: RenderResult) -> RenderResult {
     }
 }
 
-#[allow(dead_code)]
-enum ComponentsLayout {
-    Top,
-    Middle,
-    Bottom,
-    Left,
-    Center,
-    Right,
-}
-
 fn render_components(layout: ComponentsLayout, components: &[RenderResult]) -> RenderResult {
     let cr = Context::new(&*RecordingSurface::create(Content::ColorAlpha, None).unwrap());
     cr.save();
@@ -280,57 +170,32 @@ fn render_components(layout: ComponentsLayout, components: &[RenderResult]) -> R
     }
 }
 
-fn render_value<RenderPart: FnMut(usize) -> RenderResult>(value: Value, mut render_part: RenderPart) -> RenderResult {
-    #[allow(clippy::if_same_then_else)]
-    if value.is::<HoldValueInner>() {
-        render_underline(render_part(0), 0.8, 0.0, 0.0, 1.0)
-    } else if value.is::<ReleaseValueInner>() {
-        render_underline(render_part(0), 0.0, 0.8, 0.0, 1.0)
-    } else if value.is::<AssignmentValueInner>() {
-        render_components(ComponentsLayout::Middle, &[render_part(1), render_text(" ← "), render_part(0)])
-    } else if value.is::<DereferenceValueInner>() {
-        render_components(ComponentsLayout::Middle, &[render_text("∗"), render_empty(1.0, 0.0), render_part(0)])
-    } else if let Some(value_inner) = value.try_downcast::<ExecutableSequenceValueInner>() {
-        render_components(
-            ComponentsLayout::Left,
-            &std::iter::once(render_text("{"))
-                .chain(
-                    (0..value_inner.inner.len())
-                        .map(|part_index| render_components(ComponentsLayout::Middle, &[render_empty(20.0, 0.0), render_part(part_index)]))
-                        .intersperse(render_empty(0.0, 1.0)),
-                )
-                .chain(std::iter::once(render_text("}")))
+fn render_rendering<RenderPart: FnMut(usize) -> RenderResult>(rendering: Rendering, render_part: &mut RenderPart) -> RenderResult {
+    match rendering {
+        Rendering::Empty { width, height } => render_empty(width, height),
+        Rendering::Text(text) => render_text(&text),
+        Rendering::Part(part_index) => render_part(part_index),
+        Rendering::Underline {
+            inner,
+            red,
+            green,
+            blue,
+            alpha,
+        } => render_underline(render_rendering(*inner, render_part), red, green, blue, alpha),
+        Rendering::Components { layout, components } => render_components(
+            layout,
+            &components
+                .into_iter()
+                .map(|component| render_rendering(component, render_part))
                 .collect::<Vec<_>>(),
-        )
-    } else if value.is::<ExecutableFunctionValueInner>() {
-        render_components(ComponentsLayout::Middle, &[render_part(0), render_text(" → "), render_part(1)])
-    } else if value.is::<FunctionApplicationValueInner>() {
-        render_components(ComponentsLayout::Middle, &[render_part(0), render_part(1)])
-    } else if value.is::<IntrinsicCallValueInner>() {
-        render_components(ComponentsLayout::Middle, &[render_part(0), render_part(1)])
-    } else if let Some(value_inner) = value.try_downcast::<TupleValueInner>() {
-        render_components(
-            ComponentsLayout::Middle,
-            &std::iter::once(render_text("("))
-                .chain(
-                    (0..value_inner.inner.len())
-                        .map(|part_index| render_part(part_index))
-                        .intersperse(render_components(ComponentsLayout::Bottom, &[render_text(", ")])),
-                )
-                .chain(std::iter::once(render_text(")")))
-                .collect::<Vec<_>>(),
-        )
-    } else if let Some(_) = value.try_downcast::<NullValueInner>() {
-        render_text("null")
-    } else if let Some(value_inner) = value.try_downcast::<SymbolValueInner>() {
-        render_text(&value_inner.name)
-    } else if let Some(value_inner) = value.try_downcast::<FloatingPointNumberValueInner>() {
-        render_text(&value_inner.inner.to_string())
-    } else {
-        unreachable!()
+        ),
     }
 }
 
+fn render_value<RenderPart: FnMut(usize) -> RenderResult>(value: Value, mut render_part: RenderPart) -> RenderResult {
+    render_rendering(value_kind::kind_or_unregistered(&value).render(&value), &mut render_part)
+}
+
 fn render(value: Value, selection: Option<&[usize]>) -> RenderResult {
     let render_result = render_value(value.clone(), |part_index| {
         render(
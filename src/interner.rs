@@ -0,0 +1,138 @@
+use crate::{data::*, structural, value::WeakValue, Value};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+// Nodes are keyed shallowly, by their kind, their leaf data and the identities of their already interned parts, so that looking
+// up a node never walks more than one level of the tree. Parts are compared as values are, so symbols match by name: rebuilding a
+// node keeps the original when its parts only differ by symbols of the same name.
+#[derive(Default)]
+pub struct Interner {
+    nodes: HashMap<u64, Vec<WeakValue>>,
+}
+
+fn shallow_hash(value: &Value, parts: &[Value]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    structural::leaf_hash(value).hash(&mut hasher);
+    parts.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn shallow_eq(a: &Value, a_parts: &[Value], b: &Value) -> bool {
+    if kind_name(a) != kind_name(b) || !structural::leaves_eq(a, b) {
+        return false;
+    }
+    let b_parts = get_parts(b.clone());
+    a_parts.len() == b_parts.len() && a_parts.iter().zip(&b_parts).all(|(a_part, b_part)| a_part == b_part)
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Interner { nodes: HashMap::new() }
+    }
+
+    fn intern_node(&mut self, value: Value) -> Value {
+        // Closures carry an environment that is not one of their parts, so two of them with the same parts can still differ.
+        if value.is::<ClosureValueInner>() {
+            return value;
+        }
+        let parts = get_parts(value.clone());
+        let bucket = self.nodes.entry(shallow_hash(&value, &parts)).or_default();
+        bucket.retain(|node| !node.is_dead());
+        for node in bucket.iter() {
+            if let Some(node) = node.upgrade() {
+                if shallow_eq(&value, &parts, &node) {
+                    return node;
+                }
+            }
+        }
+        bucket.push(value.downgrade());
+        value
+    }
+
+    pub fn intern(&mut self, value: Value) -> Value {
+        enum Task {
+            Visit(Value),
+            Rebuild(Value, usize),
+        }
+        let mut interned = HashMap::new();
+        let mut tasks = vec![Task::Visit(value)];
+        let mut results = Vec::new();
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Visit(value) => {
+                    if let Some(result) = interned.get(&value) {
+                        results.push(Value::clone(result));
+                        continue;
+                    }
+                    let parts = get_parts(value.clone());
+                    tasks.push(Task::Rebuild(value, parts.len()));
+                    tasks.extend(parts.into_iter().rev().map(Task::Visit));
+                }
+                Task::Rebuild(value, count) => {
+                    let parts = results.split_off(results.len() - count);
+                    let rebuilt = if parts.is_empty() {
+                        value.clone()
+                    } else {
+                        replace_parts(value.clone(), &parts)
+                    };
+                    let result = self.intern_node(rebuilt);
+                    interned.insert(value, result.clone());
+                    results.push(result);
+                }
+            }
+        }
+        results.pop().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::tests::*;
+
+    fn tree() -> Value {
+        let (add, x) = (symbol("add"), symbol("x"));
+        tuple(vec![intrinsic_call(&add, vec![integer(1), dereference(&x)]), integer(1), x])
+    }
+
+    #[test]
+    fn equal_trees_share_nodes() {
+        let mut interner = Interner::new();
+        let first = interner.intern(tree());
+        let second = interner.intern(tree());
+        assert!(first.ptr_eq(&second));
+        assert!(structural::structural_eq(&first, &tree()));
+        // Equal subtrees share a node wherever they appear, even within one tree.
+        let parts = get_parts(first.clone());
+        assert!(get_parts(get_parts(parts[0].clone())[1].clone())[0].ptr_eq(&parts[1]));
+        let other = interner.intern(tuple(vec![integer(2)]));
+        assert!(!other.ptr_eq(&first));
+        assert!(!get_parts(other)[0].ptr_eq(&parts[1]));
+    }
+
+    // Two closures with the same parts may still capture different environments, so they are left alone.
+    #[test]
+    fn closures_are_not_interned() {
+        let x = symbol("x");
+        let mut execution_context = arithmetic_context();
+        let first = evaluate(&mut execution_context, function(vec![x.clone()], dereference(&x))).unwrap();
+        let second = evaluate(&mut execution_context, function(vec![x.clone()], dereference(&x))).unwrap();
+        let mut interner = Interner::new();
+        assert!(!interner.intern(first).ptr_eq(&interner.intern(second)));
+    }
+
+    #[test]
+    fn evaluation_results_are_interned() {
+        let add = symbol("add");
+        let mut execution_context = arithmetic_context();
+        execution_context.interner = Some(Interner::new());
+        let sum = || tuple(vec![intrinsic_call(&add, vec![integer(1), integer(2)]), integer(3)]);
+        let first = evaluate(&mut execution_context, sum()).unwrap();
+        let second = evaluate(&mut execution_context, sum()).unwrap();
+        assert!(first.ptr_eq(&second));
+        let parts = get_parts(first);
+        assert!(parts[0].ptr_eq(&parts[1]));
+    }
+}
@@ -0,0 +1,1131 @@
+use crate::{
+    data::*,
+    serialization::SerializationError,
+    value::{Lock, Shared},
+    value_kind::{ComponentsLayout, JsonValue, Rendering, ValueKind},
+    Value,
+};
+use itertools::Itertools;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::Zero;
+use serde_json::{json, Map};
+use std::{
+    any::TypeId,
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    hash::Hasher,
+    str::FromStr,
+};
+
+type JsonMap = Map<String, JsonValue>;
+
+fn object(value: JsonValue) -> JsonMap {
+    match value {
+        JsonValue::Object(map) => map,
+        _ => unreachable!(),
+    }
+}
+
+fn field<'a>(entry: &'a JsonValue, name: &str) -> Result<&'a JsonValue, SerializationError> {
+    entry.get(name).ok_or(SerializationError::Malformed)
+}
+
+fn str_field<'a>(entry: &'a JsonValue, name: &str) -> Result<&'a str, SerializationError> {
+    field(entry, name)?.as_str().ok_or(SerializationError::Malformed)
+}
+
+fn array_field<'a>(entry: &'a JsonValue, name: &str) -> Result<&'a Vec<JsonValue>, SerializationError> {
+    field(entry, name)?.as_array().ok_or(SerializationError::Malformed)
+}
+
+fn parse_field<T: FromStr>(entry: &JsonValue, name: &str) -> Result<T, SerializationError> {
+    str_field(entry, name)?.parse().map_err(|_| SerializationError::Malformed)
+}
+
+fn write_str(hasher: &mut dyn Hasher, text: &str) {
+    hasher.write(&(text.len() as u64).to_le_bytes());
+    hasher.write(text.as_bytes());
+}
+
+fn normalize_float(inner: f64) -> u64 {
+    if inner.is_nan() {
+        f64::NAN.to_bits()
+    } else if inner == 0.0 {
+        0.0f64.to_bits()
+    } else {
+        inner.to_bits()
+    }
+}
+
+fn render_list(open: &str, close: &str, items: impl Iterator<Item = Rendering>) -> Rendering {
+    Rendering::components(
+        ComponentsLayout::Middle,
+        std::iter::once(Rendering::text(open))
+            .chain(Itertools::intersperse(
+                items,
+                Rendering::components(ComponentsLayout::Bottom, vec![Rendering::text(", ")]),
+            ))
+            .chain(std::iter::once(Rendering::text(close)))
+            .collect(),
+    )
+}
+
+// Defines the kind of a value whose data is a fixed set of `Value` fields, which are also its parts, in order.
+macro_rules! fields_value_kind {
+    ($kind:ident, $value_inner:ident, $name:literal, [$($field:ident),*], $render:expr) => {
+        struct $kind;
+
+        impl ValueKind for $kind {
+            fn name(&self) -> &'static str {
+                $name
+            }
+
+            fn inner_type_id(&self) -> TypeId {
+                TypeId::of::<$value_inner>()
+            }
+
+            fn parts(&self, value: &Value) -> Vec<Value> {
+                let value_inner = value.downcast::<$value_inner>();
+                vec![$(value_inner.$field.clone()),*]
+            }
+
+            fn replace_parts(&self, value: Value, parts: &[Value]) -> Value {
+                assert_eq!(parts.len(), <[&str]>::len(&[$(stringify!($field)),*]));
+                let value_inner = value.downcast::<$value_inner>();
+                let mut parts = parts.iter().cloned();
+                $(let $field = parts.next().unwrap();)*
+                if $($field == value_inner.$field)&&* {
+                    value
+                } else {
+                    Value::new($value_inner { $($field),* })
+                }
+            }
+
+            fn serialize(&self, value: &Value, f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+                let value_inner = value.downcast::<$value_inner>();
+                let mut entry = JsonMap::new();
+                $(entry.insert(stringify!($field).to_owned(), f(value_inner.$field.clone()));)*
+                entry
+            }
+
+            fn deserialize(&self, entry: &JsonValue, f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+                Ok(Value::new($value_inner {
+                    $($field: f(field(entry, stringify!($field))?.clone())?),*
+                }))
+            }
+
+            fn render(&self, _value: &Value) -> Rendering {
+                $render
+            }
+        }
+    };
+}
+
+fields_value_kind!(
+    HoldKind,
+    HoldValueInner,
+    "Hold",
+    [inner],
+    Rendering::Underline {
+        inner: Box::new(Rendering::Part(0)),
+        red: 0.8,
+        green: 0.0,
+        blue: 0.0,
+        alpha: 1.0,
+    }
+);
+
+fields_value_kind!(
+    ReleaseKind,
+    ReleaseValueInner,
+    "Release",
+    [inner],
+    Rendering::Underline {
+        inner: Box::new(Rendering::Part(0)),
+        red: 0.0,
+        green: 0.8,
+        blue: 0.0,
+        alpha: 1.0,
+    }
+);
+
+fields_value_kind!(
+    AssignmentKind,
+    AssignmentValueInner,
+    "Assignment",
+    [source, target],
+    Rendering::components(ComponentsLayout::Middle, vec![Rendering::Part(1), Rendering::text(" ← "), Rendering::Part(0)],)
+);
+
+fields_value_kind!(
+    DereferenceKind,
+    DereferenceValueInner,
+    "Dereference",
+    [inner],
+    Rendering::components(
+        ComponentsLayout::Middle,
+        vec![Rendering::text("∗"), Rendering::Empty { width: 1.0, height: 0.0 }, Rendering::Part(0)],
+    )
+);
+
+fields_value_kind!(
+    ExecutableFunctionKind,
+    ExecutableFunctionValueInner,
+    "ExecutableFunction",
+    [arguments, body],
+    Rendering::components(ComponentsLayout::Middle, vec![Rendering::Part(0), Rendering::text(" → "), Rendering::Part(1)],)
+);
+
+fields_value_kind!(
+    IfKind,
+    IfValueInner,
+    "If",
+    [condition, consequent, alternative],
+    Rendering::components(
+        ComponentsLayout::Middle,
+        vec![
+            Rendering::text("if "),
+            Rendering::Part(0),
+            Rendering::text(" then "),
+            Rendering::Part(1),
+            Rendering::text(" else "),
+            Rendering::Part(2),
+        ],
+    )
+);
+
+fields_value_kind!(
+    FunctionApplicationKind,
+    FunctionApplicationValueInner,
+    "FunctionApplication",
+    [function, arguments],
+    Rendering::components(ComponentsLayout::Middle, vec![Rendering::Part(0), Rendering::Part(1)],)
+);
+
+fields_value_kind!(
+    IntrinsicCallKind,
+    IntrinsicCallValueInner,
+    "IntrinsicCall",
+    [intrinsic, arguments],
+    Rendering::components(ComponentsLayout::Middle, vec![Rendering::Part(0), Rendering::Part(1)],)
+);
+
+fields_value_kind!(
+    ThrowKind,
+    ThrowValueInner,
+    "Throw",
+    [tag, inner],
+    Rendering::components(
+        ComponentsLayout::Middle,
+        vec![Rendering::text("throw "), Rendering::Part(0), Rendering::text(" "), Rendering::Part(1)],
+    )
+);
+
+fields_value_kind!(
+    CatchKind,
+    CatchValueInner,
+    "Catch",
+    [tag, inner, handler],
+    Rendering::components(
+        ComponentsLayout::Middle,
+        vec![
+            Rendering::text("try "),
+            Rendering::Part(1),
+            Rendering::text(" catch "),
+            Rendering::Part(0),
+            Rendering::text(" with "),
+            Rendering::Part(2),
+        ],
+    )
+);
+
+fields_value_kind!(
+    FinallyKind,
+    FinallyValueInner,
+    "Finally",
+    [inner, cleanup],
+    Rendering::components(
+        ComponentsLayout::Middle,
+        vec![Rendering::text("try "), Rendering::Part(0), Rendering::text(" finally "), Rendering::Part(1)],
+    )
+);
+
+fields_value_kind!(
+    WhileKind,
+    WhileValueInner,
+    "While",
+    [condition, body],
+    Rendering::components(
+        ComponentsLayout::Middle,
+        vec![Rendering::text("while "), Rendering::Part(0), Rendering::text(" do "), Rendering::Part(1)],
+    )
+);
+
+fields_value_kind!(
+    ForKind,
+    ForValueInner,
+    "For",
+    [variable, start, end, step, body],
+    Rendering::components(
+        ComponentsLayout::Middle,
+        vec![
+            Rendering::text("for "),
+            Rendering::Part(0),
+            Rendering::text(" from "),
+            Rendering::Part(1),
+            Rendering::text(" to "),
+            Rendering::Part(2),
+            Rendering::text(" by "),
+            Rendering::Part(3),
+            Rendering::text(" do "),
+            Rendering::Part(4),
+        ],
+    )
+);
+
+fields_value_kind!(
+    DoKind,
+    DoValueInner,
+    "Do",
+    [variable, elements, body],
+    Rendering::components(
+        ComponentsLayout::Middle,
+        vec![
+            Rendering::text("for "),
+            Rendering::Part(0),
+            Rendering::text(" in "),
+            Rendering::Part(1),
+            Rendering::text(" do "),
+            Rendering::Part(2),
+        ],
+    )
+);
+
+fields_value_kind!(
+    ResetKind,
+    ResetValueInner,
+    "Reset",
+    [inner],
+    Rendering::components(ComponentsLayout::Middle, vec![Rendering::text("reset "), Rendering::Part(0)],)
+);
+
+fields_value_kind!(
+    ShiftKind,
+    ShiftValueInner,
+    "Shift",
+    [handler],
+    Rendering::components(ComponentsLayout::Middle, vec![Rendering::text("shift "), Rendering::Part(0)],)
+);
+
+fields_value_kind!(
+    PatternKind,
+    PatternValueInner,
+    "Pattern",
+    [name, inner],
+    Rendering::components(ComponentsLayout::Middle, vec![Rendering::Part(0), Rendering::Part(1)],)
+);
+
+fields_value_kind!(
+    RuleKind,
+    RuleValueInner,
+    "Rule",
+    [left, right],
+    Rendering::components(ComponentsLayout::Middle, vec![Rendering::Part(0), Rendering::text(" ⇒ "), Rendering::Part(1)],)
+);
+
+struct ExecutableSequenceKind;
+
+impl ValueKind for ExecutableSequenceKind {
+    fn name(&self) -> &'static str {
+        "ExecutableSequence"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<ExecutableSequenceValueInner>()
+    }
+
+    fn parts(&self, value: &Value) -> Vec<Value> {
+        value.downcast::<ExecutableSequenceValueInner>().inner.clone()
+    }
+
+    fn replace_parts(&self, value: Value, parts: &[Value]) -> Value {
+        if parts == value.downcast::<ExecutableSequenceValueInner>().inner.as_slice() {
+            value
+        } else {
+            Value::new(ExecutableSequenceValueInner { inner: parts.to_vec() })
+        }
+    }
+
+    fn serialize(&self, value: &Value, f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        object(json!({
+            "inner": JsonValue::Array(value.downcast::<ExecutableSequenceValueInner>().inner.iter().cloned().map(f).collect()),
+        }))
+    }
+
+    fn deserialize(&self, entry: &JsonValue, f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        Ok(Value::new(ExecutableSequenceValueInner {
+            inner: array_field(entry, "inner")?.iter().cloned().map(f).collect::<Result<_, _>>()?,
+        }))
+    }
+
+    fn render(&self, value: &Value) -> Rendering {
+        Rendering::components(
+            ComponentsLayout::Left,
+            std::iter::once(Rendering::text("{"))
+                .chain(Itertools::intersperse(
+                    (0..value.downcast::<ExecutableSequenceValueInner>().inner.len()).map(|part_index| {
+                        Rendering::components(
+                            ComponentsLayout::Middle,
+                            vec![Rendering::Empty { width: 20.0, height: 0.0 }, Rendering::Part(part_index)],
+                        )
+                    }),
+                    Rendering::Empty { width: 0.0, height: 1.0 },
+                ))
+                .chain(std::iter::once(Rendering::text("}")))
+                .collect(),
+        )
+    }
+}
+
+thread_local! {
+    // Environments whose bindings are being serialized by this thread. A closure bound in its own environment is serialized
+    // recursively by `serialize_readable`, which would otherwise not terminate.
+    static SERIALIZING_ENVIRONMENTS: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+}
+
+fn serialize_environment(mut environment: Option<Shared<Lock<Environment>>>, f: &mut dyn FnMut(Value) -> JsonValue) -> JsonValue {
+    let mut frames = Vec::new();
+    let mut entered = Vec::new();
+    while let Some(current) = environment {
+        let address = Shared::as_ptr(&current) as *const u8 as usize;
+        if !SERIALIZING_ENVIRONMENTS.with(|serializing| serializing.borrow_mut().insert(address)) {
+            frames.push(JsonValue::String("…".to_owned()));
+            break;
+        }
+        entered.push(address);
+        // The lock is released before serializing the values, since they may be closures over this same environment.
+        let (values, parent) = {
+            let current = current.borrow();
+            (current.values.clone(), current.parent.clone())
+        };
+        frames.push(JsonValue::Array(
+            values
+                .into_iter()
+                .map(|(symbol, value)| {
+                    json!({
+                        "symbol": f(symbol),
+                        "value": f(value),
+                    })
+                })
+                .collect(),
+        ));
+        environment = parent;
+    }
+    SERIALIZING_ENVIRONMENTS.with(|serializing| {
+        let mut serializing = serializing.borrow_mut();
+        for address in entered {
+            serializing.remove(&address);
+        }
+    });
+    JsonValue::Array(frames)
+}
+
+fn deserialize_environment(
+    frames: &JsonValue,
+    f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>,
+) -> Result<Option<Shared<Lock<Environment>>>, SerializationError> {
+    let mut environment = None;
+    for frame in frames.as_array().ok_or(SerializationError::Malformed)?.iter().rev() {
+        let mut values = HashMap::new();
+        for binding in frame.as_array().ok_or(SerializationError::Malformed)? {
+            values.insert(f(field(binding, "symbol")?.clone())?, f(field(binding, "value")?.clone())?);
+        }
+        environment = Some(Shared::new(Lock::new(Environment { values, parent: environment })));
+    }
+    Ok(environment)
+}
+
+struct ClosureKind;
+
+impl ValueKind for ClosureKind {
+    fn name(&self) -> &'static str {
+        "Closure"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<ClosureValueInner>()
+    }
+
+    fn parts(&self, value: &Value) -> Vec<Value> {
+        let value_inner = value.downcast::<ClosureValueInner>();
+        vec![value_inner.arguments.clone(), value_inner.body.clone()]
+    }
+
+    fn replace_parts(&self, value: Value, parts: &[Value]) -> Value {
+        assert_eq!(parts.len(), 2);
+        let value_inner = value.downcast::<ClosureValueInner>();
+        let arguments = parts[0].clone();
+        let body = parts[1].clone();
+        if arguments == value_inner.arguments && body == value_inner.body {
+            value
+        } else {
+            Value::new(ClosureValueInner {
+                arguments,
+                body,
+                environment: value_inner.environment.clone(),
+            })
+        }
+    }
+
+    fn serialize(&self, value: &Value, f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        let value_inner = value.downcast::<ClosureValueInner>();
+        object(json!({
+            "arguments": f(value_inner.arguments.clone()),
+            "body": f(value_inner.body.clone()),
+            "environment": serialize_environment(value_inner.environment.clone(), f),
+        }))
+    }
+
+    fn deserialize(&self, entry: &JsonValue, f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        Ok(Value::new(ClosureValueInner {
+            arguments: f(field(entry, "arguments")?.clone())?,
+            body: f(field(entry, "body")?.clone())?,
+            environment: deserialize_environment(field(entry, "environment")?, f)?,
+        }))
+    }
+
+    fn render(&self, _value: &Value) -> Rendering {
+        Rendering::components(ComponentsLayout::Middle, vec![Rendering::Part(0), Rendering::text(" ↦ "), Rendering::Part(1)])
+    }
+
+    // Closures with the same parts in different environments are different functions, so they are ordered by their environments.
+    fn compare_leaves(&self, a: &Value, b: &Value) -> Ordering {
+        let environment = |value: &Value| {
+            value
+                .downcast::<ClosureValueInner>()
+                .environment
+                .as_ref()
+                .map(|environment| Shared::as_ptr(environment) as usize)
+        };
+        environment(a).cmp(&environment(b))
+    }
+}
+
+struct TupleKind;
+
+impl ValueKind for TupleKind {
+    fn name(&self) -> &'static str {
+        "Tuple"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<TupleValueInner>()
+    }
+
+    fn parts(&self, value: &Value) -> Vec<Value> {
+        value.downcast::<TupleValueInner>().inner.clone()
+    }
+
+    fn replace_parts(&self, value: Value, parts: &[Value]) -> Value {
+        if parts == value.downcast::<TupleValueInner>().inner.as_slice() {
+            value
+        } else {
+            Value::new(TupleValueInner { inner: parts.to_vec() })
+        }
+    }
+
+    fn serialize(&self, value: &Value, f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        object(json!({
+            "inner": JsonValue::Array(value.downcast::<TupleValueInner>().inner.iter().cloned().map(f).collect()),
+        }))
+    }
+
+    fn deserialize(&self, entry: &JsonValue, f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        Ok(Value::new(TupleValueInner {
+            inner: array_field(entry, "inner")?.iter().cloned().map(f).collect::<Result<_, _>>()?,
+        }))
+    }
+
+    fn render(&self, value: &Value) -> Rendering {
+        render_list("(", ")", (0..value.downcast::<TupleValueInner>().inner.len()).map(Rendering::Part))
+    }
+}
+
+struct AssociationKind;
+
+impl ValueKind for AssociationKind {
+    fn name(&self) -> &'static str {
+        "Association"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<AssociationValueInner>()
+    }
+
+    fn parts(&self, value: &Value) -> Vec<Value> {
+        value.downcast::<AssociationValueInner>().parts()
+    }
+
+    fn replace_parts(&self, value: Value, parts: &[Value]) -> Value {
+        assert_eq!(parts.len() % 2, 0);
+        if parts == self.parts(&value).as_slice() {
+            value
+        } else {
+            Value::new(AssociationValueInner {
+                inner: parts.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect(),
+            })
+        }
+    }
+
+    fn serialize(&self, value: &Value, f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        object(json!({
+            "inner": JsonValue::Array(
+                value
+                    .downcast::<AssociationValueInner>()
+                    .inner
+                    .iter()
+                    .map(|(key, value)| {
+                        json!({
+                            "key": f(key.clone()),
+                            "value": f(value.clone()),
+                        })
+                    })
+                    .collect(),
+            ),
+        }))
+    }
+
+    fn deserialize(&self, entry: &JsonValue, f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        Ok(Value::new(AssociationValueInner {
+            inner: array_field(entry, "inner")?
+                .iter()
+                .map(|pair| Ok((f(field(pair, "key")?.clone())?, f(field(pair, "value")?.clone())?)))
+                .collect::<Result<_, SerializationError>>()?,
+        }))
+    }
+
+    fn render(&self, value: &Value) -> Rendering {
+        render_list(
+            "⟨",
+            "⟩",
+            (0..value.downcast::<AssociationValueInner>().inner.len()).map(|pair_index| {
+                Rendering::components(
+                    ComponentsLayout::Middle,
+                    vec![Rendering::Part(pair_index * 2), Rendering::text(": "), Rendering::Part(pair_index * 2 + 1)],
+                )
+            }),
+        )
+    }
+}
+
+struct BlankKind;
+
+impl ValueKind for BlankKind {
+    fn name(&self) -> &'static str {
+        "Blank"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<BlankValueInner>()
+    }
+
+    fn serialize(&self, value: &Value, _f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        object(json!({
+            "kind": match &value.downcast::<BlankValueInner>().kind {
+                Some(kind) => JsonValue::String(kind.clone()),
+                None => JsonValue::Null,
+            },
+        }))
+    }
+
+    fn deserialize(&self, entry: &JsonValue, _f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        Ok(Value::new(BlankValueInner {
+            kind: entry["kind"].as_str().map(|kind| kind.to_owned()),
+        }))
+    }
+
+    fn render(&self, value: &Value) -> Rendering {
+        Rendering::Text(format!("_{}", value.downcast::<BlankValueInner>().kind.as_deref().unwrap_or("")))
+    }
+
+    fn leaves_eq(&self, a: &Value, b: &Value) -> bool {
+        a.downcast::<BlankValueInner>().kind == b.downcast::<BlankValueInner>().kind
+    }
+
+    fn hash_leaf(&self, value: &Value, hasher: &mut dyn Hasher) {
+        write_str(hasher, value.downcast::<BlankValueInner>().kind.as_deref().unwrap_or(""));
+    }
+
+    fn compare_leaves(&self, a: &Value, b: &Value) -> Ordering {
+        a.downcast::<BlankValueInner>().kind.cmp(&b.downcast::<BlankValueInner>().kind)
+    }
+}
+
+struct RepeatedPatternKind;
+
+impl ValueKind for RepeatedPatternKind {
+    fn name(&self) -> &'static str {
+        "RepeatedPattern"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<RepeatedPatternValueInner>()
+    }
+
+    fn parts(&self, value: &Value) -> Vec<Value> {
+        vec![value.downcast::<RepeatedPatternValueInner>().inner.clone()]
+    }
+
+    fn replace_parts(&self, value: Value, parts: &[Value]) -> Value {
+        assert_eq!(parts.len(), 1);
+        let value_inner = value.downcast::<RepeatedPatternValueInner>();
+        let inner = parts[0].clone();
+        if inner == value_inner.inner {
+            value
+        } else {
+            Value::new(RepeatedPatternValueInner {
+                inner,
+                minimum: value_inner.minimum,
+            })
+        }
+    }
+
+    fn serialize(&self, value: &Value, f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        let value_inner = value.downcast::<RepeatedPatternValueInner>();
+        object(json!({
+            "inner": f(value_inner.inner.clone()),
+            "minimum": JsonValue::Number(value_inner.minimum.into()),
+        }))
+    }
+
+    fn deserialize(&self, entry: &JsonValue, f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        Ok(Value::new(RepeatedPatternValueInner {
+            inner: f(field(entry, "inner")?.clone())?,
+            minimum: field(entry, "minimum")?.as_u64().ok_or(SerializationError::Malformed)? as usize,
+        }))
+    }
+
+    fn render(&self, value: &Value) -> Rendering {
+        let minimum = value.downcast::<RepeatedPatternValueInner>().minimum;
+        Rendering::components(
+            ComponentsLayout::Middle,
+            vec![Rendering::Part(0), Rendering::text(if minimum == 0 { "..." } else { ".." })],
+        )
+    }
+
+    fn leaves_eq(&self, a: &Value, b: &Value) -> bool {
+        a.downcast::<RepeatedPatternValueInner>().minimum == b.downcast::<RepeatedPatternValueInner>().minimum
+    }
+
+    fn hash_leaf(&self, value: &Value, hasher: &mut dyn Hasher) {
+        hasher.write(&(value.downcast::<RepeatedPatternValueInner>().minimum as u64).to_le_bytes());
+    }
+
+    fn compare_leaves(&self, a: &Value, b: &Value) -> Ordering {
+        a.downcast::<RepeatedPatternValueInner>()
+            .minimum
+            .cmp(&b.downcast::<RepeatedPatternValueInner>().minimum)
+    }
+}
+
+struct ContinuationKind;
+
+// Continuations hold evaluator frames, which have no serialized form, and are only equal to themselves.
+impl ValueKind for ContinuationKind {
+    fn name(&self) -> &'static str {
+        "Continuation"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<ContinuationValueInner>()
+    }
+
+    fn is_serializable(&self) -> bool {
+        false
+    }
+
+    fn serialize(&self, _value: &Value, _f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        JsonMap::new()
+    }
+
+    fn deserialize(&self, _entry: &JsonValue, _f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        unreachable!()
+    }
+
+    fn render(&self, _value: &Value) -> Rendering {
+        Rendering::text("continuation")
+    }
+
+    fn leaves_eq(&self, a: &Value, b: &Value) -> bool {
+        a.ptr_eq(b)
+    }
+
+    fn compare_leaves(&self, a: &Value, b: &Value) -> Ordering {
+        a.address().cmp(&b.address())
+    }
+}
+
+struct NullKind;
+
+impl ValueKind for NullKind {
+    fn name(&self) -> &'static str {
+        "Null"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<NullValueInner>()
+    }
+
+    fn serialize(&self, _value: &Value, _f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        JsonMap::new()
+    }
+
+    fn deserialize(&self, _entry: &JsonValue, _f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        Ok(Value::new(NullValueInner))
+    }
+
+    fn render(&self, _value: &Value) -> Rendering {
+        Rendering::text("null")
+    }
+}
+
+struct BreakKind;
+
+impl ValueKind for BreakKind {
+    fn name(&self) -> &'static str {
+        "Break"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<BreakValueInner>()
+    }
+
+    fn serialize(&self, _value: &Value, _f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        JsonMap::new()
+    }
+
+    fn deserialize(&self, _entry: &JsonValue, _f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        Ok(Value::new(BreakValueInner))
+    }
+
+    fn render(&self, _value: &Value) -> Rendering {
+        Rendering::text("break")
+    }
+}
+
+struct ContinueKind;
+
+impl ValueKind for ContinueKind {
+    fn name(&self) -> &'static str {
+        "Continue"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<ContinueValueInner>()
+    }
+
+    fn serialize(&self, _value: &Value, _f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        JsonMap::new()
+    }
+
+    fn deserialize(&self, _entry: &JsonValue, _f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        Ok(Value::new(ContinueValueInner))
+    }
+
+    fn render(&self, _value: &Value) -> Rendering {
+        Rendering::text("continue")
+    }
+}
+
+struct SymbolKind;
+
+impl ValueKind for SymbolKind {
+    fn name(&self) -> &'static str {
+        "Symbol"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<SymbolValueInner>()
+    }
+
+    fn serialize(&self, value: &Value, _f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        let value_inner = value.downcast::<SymbolValueInner>();
+        let mut entry = object(json!({
+            "name": JsonValue::String(value_inner.name.clone()),
+        }));
+        if value_inner.fresh {
+            entry.insert("fresh".to_owned(), JsonValue::Bool(true));
+        }
+        entry
+    }
+
+    fn deserialize(&self, entry: &JsonValue, _f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        Ok(Value::new(SymbolValueInner {
+            name: str_field(entry, "name")?.to_owned(),
+            fresh: entry["fresh"].as_bool().unwrap_or(false),
+        }))
+    }
+
+    fn render(&self, value: &Value) -> Rendering {
+        Rendering::text(&value.downcast::<SymbolValueInner>().name)
+    }
+
+    fn leaves_eq(&self, a: &Value, b: &Value) -> bool {
+        a == b
+    }
+
+    fn hash_leaf(&self, value: &Value, hasher: &mut dyn Hasher) {
+        write_str(hasher, &value.downcast::<SymbolValueInner>().name);
+    }
+
+    fn compare_leaves(&self, a: &Value, b: &Value) -> Ordering {
+        let (a, b) = (a.downcast::<SymbolValueInner>(), b.downcast::<SymbolValueInner>());
+        a.name.cmp(&b.name).then(a.fresh.cmp(&b.fresh))
+    }
+}
+
+struct StringKind;
+
+impl ValueKind for StringKind {
+    fn name(&self) -> &'static str {
+        "String"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<StringValueInner>()
+    }
+
+    fn serialize(&self, value: &Value, _f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        object(json!({
+            "inner": JsonValue::String(value.downcast::<StringValueInner>().inner.clone()),
+        }))
+    }
+
+    fn deserialize(&self, entry: &JsonValue, _f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        Ok(Value::new(StringValueInner {
+            inner: str_field(entry, "inner")?.to_owned(),
+        }))
+    }
+
+    fn render(&self, value: &Value) -> Rendering {
+        Rendering::Text(format!("{:?}", value.downcast::<StringValueInner>().inner))
+    }
+
+    fn leaves_eq(&self, a: &Value, b: &Value) -> bool {
+        a.downcast::<StringValueInner>().inner == b.downcast::<StringValueInner>().inner
+    }
+
+    fn hash_leaf(&self, value: &Value, hasher: &mut dyn Hasher) {
+        write_str(hasher, &value.downcast::<StringValueInner>().inner);
+    }
+
+    fn compare_leaves(&self, a: &Value, b: &Value) -> Ordering {
+        a.downcast::<StringValueInner>().inner.cmp(&b.downcast::<StringValueInner>().inner)
+    }
+}
+
+struct BooleanKind;
+
+impl ValueKind for BooleanKind {
+    fn name(&self) -> &'static str {
+        "Boolean"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<BooleanValueInner>()
+    }
+
+    fn serialize(&self, value: &Value, _f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        object(json!({
+            "inner": JsonValue::Bool(value.downcast::<BooleanValueInner>().inner),
+        }))
+    }
+
+    fn deserialize(&self, entry: &JsonValue, _f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        Ok(Value::new(BooleanValueInner {
+            inner: field(entry, "inner")?.as_bool().ok_or(SerializationError::Malformed)?,
+        }))
+    }
+
+    fn render(&self, value: &Value) -> Rendering {
+        Rendering::Text(value.downcast::<BooleanValueInner>().inner.to_string())
+    }
+
+    fn leaves_eq(&self, a: &Value, b: &Value) -> bool {
+        a.downcast::<BooleanValueInner>().inner == b.downcast::<BooleanValueInner>().inner
+    }
+
+    fn hash_leaf(&self, value: &Value, hasher: &mut dyn Hasher) {
+        hasher.write(&[value.downcast::<BooleanValueInner>().inner as u8]);
+    }
+
+    fn compare_leaves(&self, a: &Value, b: &Value) -> Ordering {
+        a.downcast::<BooleanValueInner>().inner.cmp(&b.downcast::<BooleanValueInner>().inner)
+    }
+}
+
+struct IntegerKind;
+
+impl ValueKind for IntegerKind {
+    fn name(&self) -> &'static str {
+        "Integer"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<IntegerValueInner>()
+    }
+
+    fn serialize(&self, value: &Value, _f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        object(json!({
+            "inner": JsonValue::String(value.downcast::<IntegerValueInner>().inner.to_string()),
+        }))
+    }
+
+    fn deserialize(&self, entry: &JsonValue, _f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        Ok(Value::new(IntegerValueInner {
+            inner: parse_field(entry, "inner")?,
+        }))
+    }
+
+    fn render(&self, value: &Value) -> Rendering {
+        Rendering::Text(value.downcast::<IntegerValueInner>().inner.to_string())
+    }
+
+    fn leaves_eq(&self, a: &Value, b: &Value) -> bool {
+        a.downcast::<IntegerValueInner>().inner == b.downcast::<IntegerValueInner>().inner
+    }
+
+    fn hash_leaf(&self, value: &Value, hasher: &mut dyn Hasher) {
+        hasher.write(&value.downcast::<IntegerValueInner>().inner.to_signed_bytes_le());
+    }
+}
+
+struct RationalKind;
+
+impl ValueKind for RationalKind {
+    fn name(&self) -> &'static str {
+        "Rational"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<RationalValueInner>()
+    }
+
+    fn serialize(&self, value: &Value, _f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        let value_inner = value.downcast::<RationalValueInner>();
+        object(json!({
+            "numerator": JsonValue::String(value_inner.inner.numer().to_string()),
+            "denominator": JsonValue::String(value_inner.inner.denom().to_string()),
+        }))
+    }
+
+    fn deserialize(&self, entry: &JsonValue, _f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        let denominator: BigInt = parse_field(entry, "denominator")?;
+        if denominator.is_zero() {
+            return Err(SerializationError::Malformed);
+        }
+        Ok(Value::new(RationalValueInner {
+            inner: BigRational::new(parse_field(entry, "numerator")?, denominator),
+        }))
+    }
+
+    fn render(&self, value: &Value) -> Rendering {
+        Rendering::Text(value.downcast::<RationalValueInner>().inner.to_string())
+    }
+
+    fn leaves_eq(&self, a: &Value, b: &Value) -> bool {
+        a.downcast::<RationalValueInner>().inner == b.downcast::<RationalValueInner>().inner
+    }
+
+    fn hash_leaf(&self, value: &Value, hasher: &mut dyn Hasher) {
+        let value_inner = value.downcast::<RationalValueInner>();
+        hasher.write(&value_inner.inner.numer().to_signed_bytes_le());
+        hasher.write(&value_inner.inner.denom().to_signed_bytes_le());
+    }
+}
+
+struct FloatingPointNumberKind;
+
+impl ValueKind for FloatingPointNumberKind {
+    fn name(&self) -> &'static str {
+        "FloatingPointNumber"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<FloatingPointNumberValueInner>()
+    }
+
+    fn serialize(&self, value: &Value, _f: &mut dyn FnMut(Value) -> JsonValue) -> JsonMap {
+        let inner = value.downcast::<FloatingPointNumberValueInner>().inner;
+        // JSON numbers cannot hold NaN or the infinities, so those are written as their names instead.
+        object(json!({
+            "inner": match serde_json::Number::from_f64(inner) {
+                Some(number) => JsonValue::Number(number),
+                None => JsonValue::String(inner.to_string()),
+            },
+        }))
+    }
+
+    fn deserialize(&self, entry: &JsonValue, _f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        let inner = match field(entry, "inner")? {
+            JsonValue::String(name) => name.parse::<f64>().ok().filter(|inner| !inner.is_finite()),
+            inner => inner.as_f64(),
+        };
+        Ok(Value::new(FloatingPointNumberValueInner {
+            inner: inner.ok_or(SerializationError::Malformed)?,
+        }))
+    }
+
+    fn render(&self, value: &Value) -> Rendering {
+        Rendering::Text(value.downcast::<FloatingPointNumberValueInner>().inner.to_string())
+    }
+
+    fn leaves_eq(&self, a: &Value, b: &Value) -> bool {
+        normalize_float(a.downcast::<FloatingPointNumberValueInner>().inner) == normalize_float(b.downcast::<FloatingPointNumberValueInner>().inner)
+    }
+
+    fn hash_leaf(&self, value: &Value, hasher: &mut dyn Hasher) {
+        hasher.write(&normalize_float(value.downcast::<FloatingPointNumberValueInner>().inner).to_le_bytes());
+    }
+}
+
+pub fn builtin_kinds() -> Vec<Box<dyn ValueKind>> {
+    vec![
+        Box::new(HoldKind),
+        Box::new(ReleaseKind),
+        Box::new(AssignmentKind),
+        Box::new(DereferenceKind),
+        Box::new(ExecutableSequenceKind),
+        Box::new(ExecutableFunctionKind),
+        Box::new(ClosureKind),
+        Box::new(IfKind),
+        Box::new(FunctionApplicationKind),
+        Box::new(IntrinsicCallKind),
+        Box::new(ThrowKind),
+        Box::new(CatchKind),
+        Box::new(FinallyKind),
+        Box::new(WhileKind),
+        Box::new(ForKind),
+        Box::new(DoKind),
+        Box::new(BreakKind),
+        Box::new(ContinueKind),
+        Box::new(ResetKind),
+        Box::new(ShiftKind),
+        Box::new(ContinuationKind),
+        Box::new(TupleKind),
+        Box::new(AssociationKind),
+        Box::new(BlankKind),
+        Box::new(PatternKind),
+        Box::new(RepeatedPatternKind),
+        Box::new(RuleKind),
+        Box::new(NullKind),
+        Box::new(SymbolKind),
+        Box::new(StringKind),
+        Box::new(BooleanKind),
+        Box::new(IntegerKind),
+        Box::new(RationalKind),
+        Box::new(FloatingPointNumberKind),
+    ]
+}
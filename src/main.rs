@@ -1,15 +1,33 @@
 #![feature(iterator_fold_self)]
 
 mod data;
+mod debugger;
+mod effects;
 mod gui;
+mod interner;
+mod kinds;
+mod memo;
+mod number;
+mod ordering;
+mod pattern_matching;
 mod serialization;
+mod structural;
 mod value;
+mod value_kind;
 
-use crate::serialization::SerializationStorage;
+use crate::{
+    debugger::{Debugger, DebuggerCommand, TerminalFrontend},
+    effects::Effects,
+    interner::Interner,
+    memo::EvaluationCache,
+    serialization::SerializationStorage,
+    value::Shared,
+};
 use data::*;
+use num_traits::ToPrimitive;
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
-    hash::{Hash, Hasher},
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
 };
 pub use value::Value;
 
@@ -17,66 +35,297 @@ macro_rules! symbol {
     ($name:ident) => {
         let $name = Value::new(SymbolValueInner {
             name: stringify!($name).to_owned(),
+            fresh: false,
         });
     };
 }
 
 macro_rules! intrinsic {
     (($execution_context:ident$(,)? $($arguments:ident),*) $body:expr) => {{
-        fn f(#[allow(unused_variables)] $execution_context: &mut ExecutionContext, arguments: Vec<Value>) -> Value {
+        fn f(#[allow(unused_variables)] $execution_context: &mut ExecutionContext, arguments: Vec<Value>) -> Result<Value, EvaluationError> {
+            let expected = <[&str]>::len(&[$(stringify!($arguments)),*]);
+            if arguments.len() != expected {
+                return Err(EvaluationError::new(
+                    Value::new(TupleValueInner { inner: arguments.clone() }),
+                    EvaluationErrorReason::ArgumentCountMismatch { expected, actual: arguments.len() },
+                ));
+            }
+            #[allow(unused_variables, unused_mut)]
             let mut arguments = arguments.into_iter();
             $(let $arguments = arguments.next().unwrap();)*
-            assert!(arguments.next().is_none());
-            $body
+            Ok($body)
         }
-        f as fn(&mut ExecutionContext, Vec<Value>) -> Value
+        f as IntrinsicFunction
     }}
 }
 
-fn main() {
+fn report(result: Result<Value, EvaluationError>) -> Value {
+    result.unwrap_or_else(|error| {
+        eprintln!("evaluation error: {}", error);
+        eprintln!("{}", serialization::serialize_readable(error.value.clone()));
+        error.value
+    })
+}
+
+fn intrinsics() -> HashMap<Value, Intrinsic> {
     let mut intrinsics = HashMap::new();
     macro_rules! define_intrinsic {
+        // Intrinsics that do not declare their effects are assumed to have all of them, so that they are never memoized or
+        // evaluated in parallel by mistake.
         ($name:ident => $($definition:tt)*) => {
+            define_intrinsic!($name [Effects::ALL] => $($definition)*);
+        };
+        ($name:ident [$effects:expr] => $($definition:tt)*) => {
             symbol!($name);
-            intrinsics.insert($name.clone(), intrinsic!($($definition)*));
+            intrinsics.insert(
+                $name.clone(),
+                Intrinsic {
+                    function: intrinsic!($($definition)*),
+                    effects: $effects,
+                },
+            );
         };
     }
-    define_intrinsic!(intrinsic_replace => (execution_context, value, from, to) {
-        let value = value.downcast::<HoldValueInner>();
-        let from = from.downcast::<HoldValueInner>();
-        let to = to.downcast::<HoldValueInner>();
+    define_intrinsic!(intrinsic_replace [Effects::PURE] => (execution_context, value, from, to) {
+        let value = expect_kind::<HoldValueInner>(&value, "Hold")?;
+        let from = expect_kind::<HoldValueInner>(&from, "Hold")?;
+        let to = expect_kind::<HoldValueInner>(&to, "Hold")?;
         Value::new(HoldValueInner { inner: replace(value.inner.clone(), from.inner.clone(), to.inner.clone()) })
     });
-    define_intrinsic!(intrinsic_make_hold => (execution_context, a) {
+    define_intrinsic!(intrinsic_replace_capture_avoiding [Effects::PURE] => (execution_context, value, from, to) {
+        let value = expect_kind::<HoldValueInner>(&value, "Hold")?;
+        let from = expect_kind::<HoldValueInner>(&from, "Hold")?;
+        let to = expect_kind::<HoldValueInner>(&to, "Hold")?;
+        Value::new(HoldValueInner {
+            inner: replace_capture_avoiding(value.inner.clone(), from.inner.clone(), to.inner.clone()),
+        })
+    });
+    define_intrinsic!(intrinsic_make_hold [Effects::PURE] => (execution_context, a) {
         Value::new(HoldValueInner { inner: a })
     });
-    define_intrinsic!(intrinsic_push => (execution_context, a) {
-        execution_context.stack.push(a);
+    define_intrinsic!(intrinsic_push [Effects::READS_STATE | Effects::WRITES_STATE] => (execution_context, a) {
+        execution_context.push(a);
         Value::new(NullValueInner)
     });
-    define_intrinsic!(intrinsic_pop => (execution_context) {
-        execution_context.stack.pop().unwrap()
+    define_intrinsic!(intrinsic_pop [Effects::READS_STATE | Effects::WRITES_STATE] => (execution_context) {
+        execution_context
+            .pop()
+            .ok_or_else(|| EvaluationError::new(Value::new(NullValueInner), EvaluationErrorReason::EmptyStack))?
     });
-    define_intrinsic!(intrinsic_print_hash => (execution_context, a) {
-        let mut hasher = DefaultHasher::new();
-        a.hash(&mut hasher);
-        println!("{}", hasher.finish());
+    define_intrinsic!(intrinsic_print_hash [Effects::IO] => (execution_context, a) {
+        println!("{}", structural::structural_hash(&a));
         Value::new(NullValueInner)
     });
-    define_intrinsic!(intrinsic_print => (execution_context, a) {
+    define_intrinsic!(intrinsic_print [Effects::IO] => (execution_context, a) {
         println!("{}", serialization::serialize_readable(a));
         Value::new(NullValueInner)
     });
-    define_intrinsic!(intrinsic_floating_point_number_add => (execution_context, a, b) {
+    define_intrinsic!(intrinsic_floating_point_number_add [Effects::PURE] => (execution_context, a, b) {
         Value::new(FloatingPointNumberValueInner {
-            inner: a.downcast::<FloatingPointNumberValueInner>().inner + b.downcast::<FloatingPointNumberValueInner>().inner,
+            inner: expect_kind::<FloatingPointNumberValueInner>(&a, "FloatingPointNumber")?.inner
+                + expect_kind::<FloatingPointNumberValueInner>(&b, "FloatingPointNumber")?.inner,
         })
     });
-    let mut execution_context = ExecutionContext {
-        intrinsics,
-        values: HashMap::new(),
-        stack: Vec::new(),
-    };
+    define_intrinsic!(intrinsic_equal [Effects::PURE] => (execution_context, a, b) {
+        Value::new(BooleanValueInner { inner: equal(&a, &b) })
+    });
+    define_intrinsic!(intrinsic_less_than [Effects::PURE] => (execution_context, a, b) {
+        Value::new(BooleanValueInner {
+            inner: number::compare(number::expect_number(&a)?, number::expect_number(&b)?) == Some(Ordering::Less),
+        })
+    });
+    define_intrinsic!(intrinsic_add [Effects::PURE] => (execution_context, a, b) {
+        number::add(number::expect_number(&a)?, number::expect_number(&b)?).into_value()
+    });
+    define_intrinsic!(intrinsic_subtract [Effects::PURE] => (execution_context, a, b) {
+        number::subtract(number::expect_number(&a)?, number::expect_number(&b)?).into_value()
+    });
+    define_intrinsic!(intrinsic_multiply [Effects::PURE] => (execution_context, a, b) {
+        number::multiply(number::expect_number(&a)?, number::expect_number(&b)?).into_value()
+    });
+    define_intrinsic!(intrinsic_divide [Effects::PURE] => (execution_context, a, b) {
+        number::divide(number::expect_number(&a)?, number::expect_number(&b)?)
+            .ok_or_else(|| EvaluationError::new(Value::new(TupleValueInner { inner: vec![a, b] }), EvaluationErrorReason::DivisionByZero))?
+            .into_value()
+    });
+    define_intrinsic!(intrinsic_and [Effects::PURE] => (execution_context, a, b) {
+        Value::new(BooleanValueInner {
+            inner: expect_kind::<BooleanValueInner>(&a, "Boolean")?.inner && expect_kind::<BooleanValueInner>(&b, "Boolean")?.inner,
+        })
+    });
+    define_intrinsic!(intrinsic_or [Effects::PURE] => (execution_context, a, b) {
+        Value::new(BooleanValueInner {
+            inner: expect_kind::<BooleanValueInner>(&a, "Boolean")?.inner || expect_kind::<BooleanValueInner>(&b, "Boolean")?.inner,
+        })
+    });
+    define_intrinsic!(intrinsic_not [Effects::PURE] => (execution_context, a) {
+        Value::new(BooleanValueInner {
+            inner: !expect_kind::<BooleanValueInner>(&a, "Boolean")?.inner,
+        })
+    });
+    define_intrinsic!(intrinsic_string_concatenate [Effects::PURE] => (execution_context, a, b) {
+        Value::new(StringValueInner {
+            inner: expect_kind::<StringValueInner>(&a, "String")?.inner.clone() + &expect_kind::<StringValueInner>(&b, "String")?.inner,
+        })
+    });
+    define_intrinsic!(intrinsic_string_length [Effects::PURE] => (execution_context, a) {
+        Value::new(IntegerValueInner {
+            inner: expect_kind::<StringValueInner>(&a, "String")?.inner.chars().count().into(),
+        })
+    });
+    define_intrinsic!(intrinsic_string_slice [Effects::PURE] => (execution_context, a, start, end) {
+        let a_inner = expect_kind::<StringValueInner>(&a, "String")?;
+        let start_inner = expect_kind::<IntegerValueInner>(&start, "Integer")?.inner.to_usize();
+        let end_inner = expect_kind::<IntegerValueInner>(&end, "Integer")?.inner.to_usize();
+        match (start_inner, end_inner) {
+            (Some(start_inner), Some(end_inner)) if start_inner <= end_inner && end_inner <= a_inner.inner.chars().count() => {
+                Value::new(StringValueInner {
+                    inner: a_inner.inner.chars().skip(start_inner).take(end_inner - start_inner).collect(),
+                })
+            }
+            _ => {
+                return Err(EvaluationError::new(
+                    Value::new(TupleValueInner { inner: vec![a, start, end] }),
+                    EvaluationErrorReason::IndexOutOfBounds,
+                ))
+            }
+        }
+    });
+    define_intrinsic!(intrinsic_string_split [Effects::PURE] => (execution_context, a, separator) {
+        let separator_inner = &expect_kind::<StringValueInner>(&separator, "String")?.inner;
+        Value::new(TupleValueInner {
+            inner: expect_kind::<StringValueInner>(&a, "String")?
+                .inner
+                .split(separator_inner.as_str())
+                .map(|part| Value::new(StringValueInner { inner: part.to_owned() }))
+                .collect(),
+        })
+    });
+    define_intrinsic!(intrinsic_number_to_string [Effects::PURE] => (execution_context, a) {
+        Value::new(StringValueInner {
+            inner: number::expect_number(&a)?.to_string(),
+        })
+    });
+    define_intrinsic!(intrinsic_string_to_number [Effects::PURE] => (execution_context, a) {
+        number::Number::parse(&expect_kind::<StringValueInner>(&a, "String")?.inner)
+            .ok_or_else(|| EvaluationError::new(a.clone(), EvaluationErrorReason::InvalidNumber))?
+            .into_value()
+    });
+    define_intrinsic!(intrinsic_association_lookup [Effects::PURE] => (execution_context, association, key) {
+        match expect_kind::<AssociationValueInner>(&association, "Association")?.get(&key) {
+            Some(value) => value.clone(),
+            None => return Err(EvaluationError::new(key, EvaluationErrorReason::KeyNotFound)),
+        }
+    });
+    define_intrinsic!(intrinsic_association_insert [Effects::PURE] => (execution_context, association, key, value) {
+        let mut inner = AssociationValueInner {
+            inner: expect_kind::<AssociationValueInner>(&association, "Association")?.inner.clone(),
+        };
+        inner.insert(key, value);
+        Value::new(inner)
+    });
+    define_intrinsic!(intrinsic_association_delete [Effects::PURE] => (execution_context, association, key) {
+        let mut inner = AssociationValueInner {
+            inner: expect_kind::<AssociationValueInner>(&association, "Association")?.inner.clone(),
+        };
+        inner.remove(&key);
+        Value::new(inner)
+    });
+    define_intrinsic!(intrinsic_association_keys [Effects::PURE] => (execution_context, association) {
+        Value::new(TupleValueInner {
+            inner: expect_kind::<AssociationValueInner>(&association, "Association")?
+                .inner
+                .iter()
+                .map(|(key, _)| key.clone())
+                .collect(),
+        })
+    });
+    define_intrinsic!(intrinsic_association_values [Effects::PURE] => (execution_context, association) {
+        Value::new(TupleValueInner {
+            inner: expect_kind::<AssociationValueInner>(&association, "Association")?
+                .inner
+                .iter()
+                .map(|(_, value)| value.clone())
+                .collect(),
+        })
+    });
+    define_intrinsic!(intrinsic_association_merge [Effects::PURE] => (execution_context, a, b) {
+        let mut inner = AssociationValueInner {
+            inner: expect_kind::<AssociationValueInner>(&a, "Association")?.inner.clone(),
+        };
+        for (key, value) in &expect_kind::<AssociationValueInner>(&b, "Association")?.inner {
+            inner.insert(key.clone(), value.clone());
+        }
+        Value::new(inner)
+    });
+    define_intrinsic!(intrinsic_replace_all [Effects::PURE] => (execution_context, value, rules) {
+        let value = expect_kind::<HoldValueInner>(&value, "Hold")?;
+        let rules = pattern_matching::expect_rules(&rules)?;
+        Value::new(HoldValueInner {
+            inner: pattern_matching::replace_all(value.inner.clone(), &rules),
+        })
+    });
+    define_intrinsic!(intrinsic_replace_repeated [Effects::PURE] => (execution_context, value, rules) {
+        let value = expect_kind::<HoldValueInner>(&value, "Hold")?;
+        let rules = pattern_matching::expect_rules(&rules)?;
+        Value::new(HoldValueInner {
+            inner: pattern_matching::replace_repeated(execution_context, value.inner.clone(), &rules)?,
+        })
+    });
+    define_intrinsic!(intrinsic_set_attributes [Effects::WRITES_STATE] => (execution_context, symbol, attributes) {
+        expect_kind::<SymbolValueInner>(&symbol, "Symbol")?;
+        let mut symbol_attributes = HashSet::new();
+        for attribute in &expect_kind::<TupleValueInner>(&attributes, "Tuple")?.inner {
+            let name = &expect_kind::<StringValueInner>(attribute, "String")?.inner;
+            symbol_attributes.insert(
+                Attribute::from_name(name).ok_or_else(|| EvaluationError::new(attribute.clone(), EvaluationErrorReason::UnknownAttribute))?,
+            );
+        }
+        Shared::make_mut(&mut execution_context.attributes).insert(symbol, symbol_attributes);
+        // Attributes change how applications inside cached function bodies evaluate.
+        if let Some(cache) = &mut execution_context.cache {
+            cache.clear();
+        }
+        Value::new(NullValueInner)
+    });
+    define_intrinsic!(intrinsic_sort [Effects::PURE] => (execution_context, a) {
+        let mut inner = expect_kind::<TupleValueInner>(&a, "Tuple")?
+            .inner
+            .iter()
+            .cloned()
+            .map(ordering::Ordered)
+            .collect::<Vec<_>>();
+        inner.sort();
+        Value::new(TupleValueInner {
+            inner: inner.into_iter().map(|ordered| ordered.0).collect(),
+        })
+    });
+    intrinsics
+}
+
+fn main() {
+    let mut execution_context = ExecutionContext::new(intrinsics());
+    #[cfg(feature = "parallel")]
+    if let Some(threads) = std::env::args().skip_while(|argument| argument != "--threads").nth(1) {
+        execution_context.set_threads(threads.parse().expect("invalid thread count"));
+    }
+    if std::env::args().any(|argument| argument == "--intern") {
+        execution_context.interner = Some(Interner::new());
+    }
+    if std::env::args().any(|argument| argument == "--memoize") {
+        execution_context.cache = Some(EvaluationCache::new());
+    }
+    if std::env::args().any(|argument| argument == "--debug") {
+        execution_context
+            .observers
+            .push(Box::new(Debugger::new(TerminalFrontend, DebuggerCommand::Step)));
+    }
+    symbol!(intrinsic_replace);
+    symbol!(intrinsic_make_hold);
+    symbol!(intrinsic_push);
+    symbol!(intrinsic_pop);
+    symbol!(intrinsic_print_hash);
+    symbol!(intrinsic_floating_point_number_add);
     symbol!(function_replace);
     symbol!(function_make_hold);
     symbol!(function_dynamic_scope);
@@ -88,7 +337,8 @@ fn main() {
     symbol!(argument_inner);
     symbol!(variable_a);
     symbol!(variable_b);
-    evaluate(
+    Shared::make_mut(&mut execution_context.attributes).insert(function_dynamic_scope.clone(), std::iter::once(Attribute::HoldRest).collect());
+    report(evaluate(
         &mut execution_context,
         Value::new(ExecutableSequenceValueInner {
             inner: vec![
@@ -101,7 +351,11 @@ fn main() {
                             inner: Value::new(IntrinsicCallValueInner {
                                 intrinsic: intrinsic_replace,
                                 arguments: Value::new(TupleValueInner {
-                                    inner: vec![argument_value, argument_from, argument_to],
+                                    inner: vec![
+                                        Value::new(DereferenceValueInner { inner: argument_value }),
+                                        Value::new(DereferenceValueInner { inner: argument_from }),
+                                        Value::new(DereferenceValueInner { inner: argument_to }),
+                                    ],
                                 }),
                             }),
                         }),
@@ -116,7 +370,9 @@ fn main() {
                         body: Value::new(HoldValueInner {
                             inner: Value::new(IntrinsicCallValueInner {
                                 intrinsic: intrinsic_make_hold,
-                                arguments: Value::new(TupleValueInner { inner: vec![argument_a] }),
+                                arguments: Value::new(TupleValueInner {
+                                    inner: vec![Value::new(DereferenceValueInner { inner: argument_a })],
+                                }),
                             }),
                         }),
                     }),
@@ -151,10 +407,14 @@ fn main() {
                                                                     }),
                                                                     Value::new(HoldValueInner { inner: variable_a.clone() }),
                                                                     Value::new(FunctionApplicationValueInner {
-                                                                        function: Value::new(DereferenceValueInner { inner: function_make_hold }),
+                                                                        function: Value::new(DereferenceValueInner {
+                                                                            inner: function_make_hold.clone(),
+                                                                        }),
                                                                         arguments: Value::new(TupleValueInner {
                                                                             inner: vec![Value::new(DereferenceValueInner {
-                                                                                inner: argument_symbol.clone(),
+                                                                                inner: Value::new(DereferenceValueInner {
+                                                                                    inner: argument_symbol.clone(),
+                                                                                }),
                                                                             })],
                                                                         }),
                                                                     }),
@@ -162,17 +422,24 @@ fn main() {
                                                             }),
                                                         }),
                                                         Value::new(HoldValueInner { inner: variable_b }),
-                                                        Value::new(HoldValueInner { inner: argument_symbol }),
+                                                        Value::new(FunctionApplicationValueInner {
+                                                            function: Value::new(DereferenceValueInner { inner: function_make_hold }),
+                                                            arguments: Value::new(TupleValueInner {
+                                                                inner: vec![Value::new(DereferenceValueInner { inner: argument_symbol })],
+                                                            }),
+                                                        }),
                                                     ],
                                                 }),
                                             })],
                                         }),
                                     }),
-                                    Value::new(ReleaseValueInner { inner: argument_inner }),
-                                    Value::new(ReleaseValueInner {
-                                        inner: Value::new(IntrinsicCallValueInner {
-                                            intrinsic: intrinsic_pop,
-                                            arguments: Value::new(TupleValueInner { inner: vec![] }),
+                                    Value::new(FinallyValueInner {
+                                        inner: Value::new(DereferenceValueInner { inner: argument_inner }),
+                                        cleanup: Value::new(ReleaseValueInner {
+                                            inner: Value::new(IntrinsicCallValueInner {
+                                                intrinsic: intrinsic_pop,
+                                                arguments: Value::new(TupleValueInner { inner: vec![] }),
+                                            }),
                                         }),
                                     }),
                                     Value::new(NullValueInner),
@@ -184,8 +451,8 @@ fn main() {
                 }),
             ],
         }),
-    );
-    evaluate(
+    ));
+    report(evaluate(
         &mut execution_context,
         Value::new(ExecutableSequenceValueInner {
             inner: vec![
@@ -206,21 +473,19 @@ fn main() {
                     arguments: Value::new(TupleValueInner {
                         inner: vec![
                             variable_a.clone(),
-                            Value::new(HoldValueInner {
-                                inner: Value::new(ExecutableSequenceValueInner {
-                                    inner: vec![
-                                        Value::new(AssignmentValueInner {
-                                            source: Value::new(FloatingPointNumberValueInner { inner: 2.0 }),
-                                            target: variable_a.clone(),
-                                        }),
-                                        Value::new(IntrinsicCallValueInner {
-                                            intrinsic: intrinsic_print_hash.clone(),
-                                            arguments: Value::new(TupleValueInner {
-                                                inner: vec![Value::new(DereferenceValueInner { inner: variable_a.clone() })],
-                                            }),
+                            Value::new(ExecutableSequenceValueInner {
+                                inner: vec![
+                                    Value::new(AssignmentValueInner {
+                                        source: Value::new(FloatingPointNumberValueInner { inner: 2.0 }),
+                                        target: variable_a.clone(),
+                                    }),
+                                    Value::new(IntrinsicCallValueInner {
+                                        intrinsic: intrinsic_print_hash.clone(),
+                                        arguments: Value::new(TupleValueInner {
+                                            inner: vec![Value::new(DereferenceValueInner { inner: variable_a.clone() })],
                                         }),
-                                    ],
-                                }),
+                                    }),
+                                ],
                             }),
                         ],
                     }),
@@ -233,8 +498,8 @@ fn main() {
                 }),
             ],
         }),
-    );
-    let value = evaluate(
+    ));
+    let value = report(evaluate(
         &mut execution_context,
         Value::new(IntrinsicCallValueInner {
             intrinsic: intrinsic_floating_point_number_add,
@@ -245,14 +510,161 @@ fn main() {
                 ],
             }),
         }),
-    );
+    ));
     println!("{}", serialization::serialize_readable(value.clone()));
     let mut serialization_storage = SerializationStorage::new();
-    let serialized = serialization::serialize(&mut serialization_storage, value);
-    let deserialized = serialization::deserialize(&mut serialization_storage, &serialized);
-    println!("{}", serialization::serialize_readable(deserialized));
-    gui::run(evaluate(
+    match serialization::serialize(&mut serialization_storage, value).and_then(|serialized| serialization::deserialize(&mut serialization_storage, &serialized))
+    {
+        Ok(deserialized) => println!("{}", serialization::serialize_readable(deserialized)),
+        Err(error) => eprintln!("{}", error),
+    }
+    if let Some(cache) = &execution_context.cache {
+        eprintln!("cache: {} hits, {} misses, {} invalidations", cache.hits, cache.misses, cache.invalidations);
+    }
+    gui::run(report(evaluate(
         &mut execution_context,
         Value::new(DereferenceValueInner { inner: function_dynamic_scope }),
-    ));
+    )));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::tests::{dereference, integer, intrinsic_call, symbol, tuple};
+
+    fn call(name: &str, arguments: Vec<Value>) -> Result<Value, EvaluationError> {
+        let mut execution_context = ExecutionContext::new(intrinsics());
+        evaluate(&mut execution_context, intrinsic_call(&symbol(name), arguments))
+    }
+
+    fn string(inner: &str) -> Value {
+        Value::new(StringValueInner { inner: inner.to_owned() })
+    }
+
+    fn boolean(inner: bool) -> Value {
+        Value::new(BooleanValueInner { inner })
+    }
+
+    fn assert_equal(a: &Value, b: &Value) {
+        assert!(structural::structural_eq(a, b), "{:?} is not {:?}", a, b);
+    }
+
+    fn association(entries: Vec<(Value, Value)>) -> Value {
+        Value::new(AssociationValueInner { inner: entries })
+    }
+
+    #[test]
+    fn comparisons_and_logic() {
+        let half = call("intrinsic_divide", vec![integer(1), integer(2)]).unwrap();
+        assert_equal(&call("intrinsic_less_than", vec![half.clone(), integer(1)]).unwrap(), &boolean(true));
+        assert_equal(&call("intrinsic_less_than", vec![integer(1), half.clone()]).unwrap(), &boolean(false));
+        let float = Value::new(FloatingPointNumberValueInner { inner: 0.5 });
+        assert_equal(&call("intrinsic_equal", vec![half, float]).unwrap(), &boolean(true));
+        assert_equal(&call("intrinsic_and", vec![boolean(true), boolean(false)]).unwrap(), &boolean(false));
+        assert_equal(&call("intrinsic_or", vec![boolean(true), boolean(false)]).unwrap(), &boolean(true));
+        assert_equal(&call("intrinsic_not", vec![boolean(true)]).unwrap(), &boolean(false));
+        let error = call("intrinsic_and", vec![boolean(true), integer(1)]).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::UnexpectedKind { expected: "Boolean" }));
+    }
+
+    // Only the branch that is taken is evaluated.
+    #[test]
+    fn conditionals() {
+        let mut execution_context = ExecutionContext::new(intrinsics());
+        let (taken, skipped) = (symbol("taken"), symbol("skipped"));
+        let assign = |target: &Value| {
+            Value::new(AssignmentValueInner {
+                target: target.clone(),
+                source: integer(1),
+            })
+        };
+        let program = Value::new(IfValueInner {
+            condition: intrinsic_call(&symbol("intrinsic_less_than"), vec![integer(2), integer(1)]),
+            consequent: assign(&skipped),
+            alternative: assign(&taken),
+        });
+        evaluate(&mut execution_context, program).unwrap();
+        assert!(execution_context.values.contains_key(&taken));
+        assert!(!execution_context.values.contains_key(&skipped));
+        let program = Value::new(IfValueInner {
+            condition: integer(1),
+            consequent: integer(2),
+            alternative: integer(3),
+        });
+        let error = evaluate(&mut execution_context, program).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::UnexpectedKind { expected: "Boolean" }));
+    }
+
+    // Lengths and positions count characters rather than bytes.
+    #[test]
+    fn strings() {
+        assert_equal(
+            &call("intrinsic_string_concatenate", vec![string("ab"), string("cd")]).unwrap(),
+            &string("abcd"),
+        );
+        assert_equal(&call("intrinsic_string_length", vec![string("héllo")]).unwrap(), &integer(5));
+        assert_equal(
+            &call("intrinsic_string_slice", vec![string("héllo"), integer(1), integer(3)]).unwrap(),
+            &string("él"),
+        );
+        for (start, end) in [(2, 1), (0, 6), (-1, 2)] {
+            let error = call("intrinsic_string_slice", vec![string("héllo"), integer(start), integer(end)]).unwrap_err();
+            assert!(matches!(error.reason, EvaluationErrorReason::IndexOutOfBounds));
+        }
+        let parts = call("intrinsic_string_split", vec![string("a,b,,c"), string(",")]).unwrap();
+        assert_equal(&parts, &tuple(vec![string("a"), string("b"), string(""), string("c")]));
+        let third = call("intrinsic_divide", vec![integer(1), integer(3)]).unwrap();
+        assert_equal(&call("intrinsic_number_to_string", vec![third.clone()]).unwrap(), &string("1/3"));
+        assert_equal(&call("intrinsic_string_to_number", vec![string(" 1/3 ")]).unwrap(), &third);
+        let float = Value::new(FloatingPointNumberValueInner { inner: 2.5 });
+        assert_equal(&call("intrinsic_string_to_number", vec![string("2.5")]).unwrap(), &float);
+        let error = call("intrinsic_string_to_number", vec![string("two")]).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::InvalidNumber));
+        let error = call("intrinsic_string_length", vec![symbol("text")]).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::UnexpectedKind { expected: "String" }));
+    }
+
+    // Keys are matched by value and kept in insertion order, and the entries are evaluated like the parts of a tuple.
+    #[test]
+    fn associations() {
+        let (a, b) = (symbol("a"), symbol("b"));
+        let mut execution_context = ExecutionContext::new(intrinsics());
+        let sum = intrinsic_call(&symbol("intrinsic_add"), vec![integer(1), integer(2)]);
+        let evaluated = evaluate(&mut execution_context, association(vec![(tuple(vec![a.clone()]), sum)])).unwrap();
+        assert_equal(&evaluated, &association(vec![(tuple(vec![a.clone()]), integer(3))]));
+        let looked_up = call("intrinsic_association_lookup", vec![evaluated, tuple(vec![a.clone()])]).unwrap();
+        assert_equal(&looked_up, &integer(3));
+        let first = association(vec![(a.clone(), integer(1)), (b.clone(), integer(2))]);
+        let inserted = call("intrinsic_association_insert", vec![first.clone(), a.clone(), integer(10)]).unwrap();
+        assert_equal(&inserted, &association(vec![(a.clone(), integer(10)), (b.clone(), integer(2))]));
+        let deleted = call("intrinsic_association_delete", vec![first.clone(), a.clone()]).unwrap();
+        assert_equal(&deleted, &association(vec![(b.clone(), integer(2))]));
+        assert_equal(
+            &call("intrinsic_association_keys", vec![first.clone()]).unwrap(),
+            &tuple(vec![a.clone(), b.clone()]),
+        );
+        assert_equal(
+            &call("intrinsic_association_values", vec![first.clone()]).unwrap(),
+            &tuple(vec![integer(1), integer(2)]),
+        );
+        let second = association(vec![(symbol("c"), integer(3)), (b.clone(), integer(20))]);
+        let merged = call("intrinsic_association_merge", vec![first.clone(), second]).unwrap();
+        let expected = association(vec![(a, integer(1)), (b.clone(), integer(20)), (symbol("c"), integer(3))]);
+        assert_equal(&merged, &expected);
+        let error = call("intrinsic_association_lookup", vec![deleted, symbol("a")]).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::KeyNotFound));
+        // Deleting and inserting leave the original association as it was.
+        assert_equal(&call("intrinsic_association_lookup", vec![first, b]).unwrap(), &integer(2));
+    }
+
+    #[test]
+    fn sort() {
+        let half = call("intrinsic_divide", vec![integer(1), integer(2)]).unwrap();
+        let x = symbol("x");
+        let values = tuple(vec![string("a"), integer(3), x.clone(), half.clone(), integer(-1)]);
+        let sorted = call("intrinsic_sort", vec![values]).unwrap();
+        assert_equal(&sorted, &tuple(vec![integer(-1), half, integer(3), string("a"), x.clone()]));
+        let error = call("intrinsic_sort", vec![dereference(&x)]).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::UnexpectedKind { expected: "Tuple" }));
+    }
 }
@@ -0,0 +1,293 @@
+use crate::{data::*, effects, structural, value::WeakValue, Value};
+use std::collections::{HashMap, HashSet};
+
+struct FunctionCache {
+    function: WeakValue,
+    results: HashMap<u64, Vec<(Value, Value)>>,
+}
+
+// Results of applying closures whose bodies neither write state nor do IO, keyed by the closure and the structure of the
+// arguments. The results of a closure are dropped as soon as one of the variables its body may read is assigned.
+//
+// Closures are keyed by address and held weakly, so that closures created and dropped in a loop do not accumulate here. A weak
+// reference keeps the allocation, so an address is not reused while its entry exists, and dead entries are pruned whenever the
+// maps have doubled in size.
+#[derive(Default)]
+pub struct EvaluationCache {
+    functions: HashMap<usize, FunctionCache>,
+    dependents: HashMap<Value, HashSet<usize>>,
+    // Closures found not to be memoizable.
+    unmemoizable: HashMap<usize, WeakValue>,
+    pruned_length: usize,
+    pub hits: usize,
+    pub misses: usize,
+    pub invalidations: usize,
+}
+
+impl EvaluationCache {
+    pub fn new() -> Self {
+        EvaluationCache::default()
+    }
+
+    pub fn invalidate(&mut self, symbol: &Value) {
+        if let Some(functions) = self.dependents.remove(symbol) {
+            for function in functions {
+                if self.functions.remove(&function).is_some() {
+                    self.invalidations += 1;
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.invalidations += self.functions.len();
+        self.functions.clear();
+        self.dependents.clear();
+        self.unmemoizable.clear();
+    }
+}
+
+// Closures are compared by their parts only, so arguments containing them could match arguments with different environments.
+fn contains_closure(value: &Value) -> bool {
+    let mut pending = vec![value.clone()];
+    while let Some(value) = pending.pop() {
+        if value.is::<ClosureValueInner>() {
+            return true;
+        }
+        pending.extend(get_parts(value));
+    }
+    false
+}
+
+fn prune(cache: &mut EvaluationCache) {
+    if cache.functions.len() + cache.unmemoizable.len() < 2 * cache.pruned_length {
+        return;
+    }
+    cache.functions.retain(|_, function_cache| !function_cache.function.is_dead());
+    cache.unmemoizable.retain(|_, known| !known.is_dead());
+    let functions = &cache.functions;
+    cache.dependents.retain(|_, dependents| {
+        dependents.retain(|address| functions.contains_key(address));
+        !dependents.is_empty()
+    });
+    cache.pruned_length = (cache.functions.len() + cache.unmemoizable.len()).max(16);
+}
+
+fn function_cache<'a>(cache: &'a mut EvaluationCache, execution_context: &ExecutionContext, function: &Value) -> Option<&'a mut FunctionCache> {
+    let address = function.address();
+    if !cache.functions.contains_key(&address) {
+        if cache.unmemoizable.contains_key(&address) {
+            return None;
+        }
+        prune(cache);
+        let analysis = effects::analyze(execution_context, function);
+        match analysis.dependencies {
+            Some(dependencies) if !analysis.effects.writes_state && !analysis.effects.io => {
+                for symbol in dependencies {
+                    cache.dependents.entry(symbol).or_default().insert(address);
+                }
+                cache.functions.insert(
+                    address,
+                    FunctionCache {
+                        function: function.downgrade(),
+                        results: HashMap::new(),
+                    },
+                );
+            }
+            _ => {
+                cache.unmemoizable.insert(address, function.downgrade());
+                return None;
+            }
+        }
+    }
+    cache.functions.get_mut(&address)
+}
+
+pub enum Lookup {
+    Hit(Value),
+    // The result should be passed to `store` once it is known.
+    Miss,
+    Uncacheable,
+}
+
+pub fn lookup(execution_context: &mut ExecutionContext, function: &Value, arguments: &Value) -> Lookup {
+    let mut cache = match execution_context.cache.take() {
+        Some(cache) => cache,
+        None => return Lookup::Uncacheable,
+    };
+    let result = match function_cache(&mut cache, execution_context, function) {
+        Some(function_cache) if !contains_closure(arguments) => {
+            let cached = function_cache
+                .results
+                .get(&structural::structural_hash(arguments))
+                .and_then(|results| results.iter().find(|(key, _)| structural::structural_eq(key, arguments)))
+                .map(|(_, result)| result.clone());
+            match cached {
+                Some(result) => {
+                    cache.hits += 1;
+                    Lookup::Hit(result)
+                }
+                None => {
+                    cache.misses += 1;
+                    Lookup::Miss
+                }
+            }
+        }
+        _ => Lookup::Uncacheable,
+    };
+    execution_context.cache = Some(cache);
+    result
+}
+
+pub fn store(execution_context: &mut ExecutionContext, function: &Value, arguments: Value, result: Value) {
+    // The function may have been invalidated while its body was being evaluated, in which case the result is already stale.
+    if let Some(function_cache) = execution_context.cache.as_mut().and_then(|cache| cache.functions.get_mut(&function.address())) {
+        function_cache
+            .results
+            .entry(structural::structural_hash(&arguments))
+            .or_default()
+            .push((arguments, result));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data::tests::*, effects::Effects, value::Shared};
+
+    fn intrinsic_stack_length(execution_context: &mut ExecutionContext, _arguments: Vec<Value>) -> Result<Value, EvaluationError> {
+        Ok(integer(execution_context.stack.len() as i64))
+    }
+
+    // Memoized applications in tail position still replace the enclosing application, and every one of them is cached.
+    #[test]
+    fn memoized_tail_calls() {
+        let (add, subtract, equal) = (symbol("add"), symbol("subtract"), symbol("equal"));
+        let mut execution_context = arithmetic_context();
+        execution_context.budget.max_depth = Some(100);
+        execution_context.cache = Some(EvaluationCache::new());
+        let (sum_to, n, total) = (symbol("sum_to"), symbol("n"), symbol("total"));
+        let body = Value::new(IfValueInner {
+            condition: intrinsic_call(&equal, vec![dereference(&n), integer(0)]),
+            consequent: dereference(&total),
+            alternative: apply(
+                &sum_to,
+                vec![
+                    intrinsic_call(&subtract, vec![dereference(&n), integer(1)]),
+                    intrinsic_call(&add, vec![dereference(&total), dereference(&n)]),
+                ],
+            ),
+        });
+        let program = sequence(vec![
+            assign(&sum_to, function(vec![n.clone(), total.clone()], body)),
+            apply(&sum_to, vec![integer(1_000_000), integer(0)]),
+        ]);
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(crate::data::equal(&result, &integer(500_000_500_000)));
+        assert!(execution_context.environment.is_none());
+        assert_eq!(execution_context.depth, 0);
+        // Any application in the chain is now a hit.
+        let result = evaluate(&mut execution_context, apply(&sum_to, vec![integer(500_000), integer(375_000_250_000)])).unwrap();
+        assert!(crate::data::equal(&result, &integer(500_000_500_000)));
+        let cache = execution_context.cache.as_ref().unwrap();
+        assert_eq!((cache.misses, cache.hits), (1_000_001, 1));
+    }
+
+    #[test]
+    fn memoized_fibonacci() {
+        let (add, subtract, equal) = (symbol("add"), symbol("subtract"), symbol("equal"));
+        let mut execution_context = arithmetic_context();
+        execution_context.cache = Some(EvaluationCache::new());
+        let fibonacci = symbol("fibonacci");
+        let n = symbol("n");
+        let body = Value::new(IfValueInner {
+            condition: intrinsic_call(&equal, vec![dereference(&n), integer(0)]),
+            consequent: integer(0),
+            alternative: Value::new(IfValueInner {
+                condition: intrinsic_call(&equal, vec![dereference(&n), integer(1)]),
+                consequent: integer(1),
+                alternative: intrinsic_call(
+                    &add,
+                    vec![
+                        apply(&fibonacci, vec![intrinsic_call(&subtract, vec![dereference(&n), integer(1)])]),
+                        apply(&fibonacci, vec![intrinsic_call(&subtract, vec![dereference(&n), integer(2)])]),
+                    ],
+                ),
+            }),
+        });
+        let program = sequence(vec![assign(&fibonacci, function(vec![n.clone()], body)), apply(&fibonacci, vec![integer(30)])]);
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(crate::data::equal(&result, &integer(832_040)));
+        // Each argument from 30 down to 0 is computed once, and the second recursive call is a hit from 3 upwards.
+        let cache = execution_context.cache.as_ref().unwrap();
+        assert_eq!((cache.misses, cache.hits, cache.invalidations), (31, 28, 0));
+    }
+
+    #[test]
+    fn memoized_results_invalidated_by_assignment() {
+        let add = symbol("add");
+        let mut execution_context = arithmetic_context();
+        execution_context.cache = Some(EvaluationCache::new());
+        let shift = symbol("shift");
+        let offset = symbol("offset");
+        let x = symbol("x");
+        let program = sequence(vec![
+            assign(&offset, integer(1)),
+            assign(
+                &shift,
+                function(vec![x.clone()], intrinsic_call(&add, vec![dereference(&x), dereference(&offset)])),
+            ),
+            tuple(vec![apply(&shift, vec![integer(1)]), apply(&shift, vec![integer(1)])]),
+        ]);
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(crate::data::equal(&result, &tuple(vec![integer(2), integer(2)])));
+        let program = sequence(vec![assign(&offset, integer(10)), apply(&shift, vec![integer(1)])]);
+        let result = evaluate(&mut execution_context, program).unwrap();
+        assert!(crate::data::equal(&result, &integer(11)));
+        let cache = execution_context.cache.as_ref().unwrap();
+        assert_eq!((cache.misses, cache.hits, cache.invalidations), (2, 1, 1));
+    }
+
+    // Reading the stack is not reading a variable, so pushing must not leave a stale result behind.
+    #[test]
+    fn functions_reading_intrinsic_state_are_not_memoized() {
+        let (stack_length, f) = (symbol("stack_length"), symbol("f"));
+        let mut execution_context = arithmetic_context();
+        Shared::make_mut(&mut execution_context.intrinsics).insert(
+            stack_length.clone(),
+            Intrinsic {
+                function: intrinsic_stack_length,
+                effects: Effects::READS_STATE,
+            },
+        );
+        execution_context.cache = Some(EvaluationCache::new());
+        evaluate(&mut execution_context, assign(&f, function(vec![], intrinsic_call(&stack_length, vec![])))).unwrap();
+        for expected in 0..3 {
+            let result = evaluate(&mut execution_context, apply(&f, vec![])).unwrap();
+            assert!(crate::data::equal(&result, &integer(expected)));
+            execution_context.push(integer(expected));
+        }
+        let cache = execution_context.cache.as_ref().unwrap();
+        assert_eq!((cache.misses, cache.hits), (0, 0));
+    }
+
+    // Closures created and dropped one after another do not stay in the cache.
+    #[test]
+    fn dropped_closures_are_pruned() {
+        let add = symbol("add");
+        let (adder, x) = (symbol("adder"), symbol("x"));
+        let mut execution_context = arithmetic_context();
+        execution_context.cache = Some(EvaluationCache::new());
+        for i in 0..1000 {
+            let program = sequence(vec![
+                assign(&adder, function(vec![x.clone()], intrinsic_call(&add, vec![dereference(&x), integer(i)]))),
+                apply(&adder, vec![integer(1)]),
+            ]);
+            let result = evaluate(&mut execution_context, program).unwrap();
+            assert!(crate::data::equal(&result, &integer(i + 1)));
+        }
+        let cache = execution_context.cache.as_ref().unwrap();
+        assert!(cache.functions.len() + cache.unmemoizable.len() < 100);
+        assert!(cache.dependents.values().all(|dependents| dependents.len() < 100));
+    }
+}
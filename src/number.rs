@@ -0,0 +1,201 @@
+use crate::{data::*, Value};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{ToPrimitive, Zero};
+use std::{cmp::Ordering, fmt};
+
+#[derive(Clone)]
+pub enum Number {
+    Integer(BigInt),
+    Rational(BigRational),
+    FloatingPoint(f64),
+}
+
+impl Number {
+    pub fn from_value(value: &Value) -> Option<Self> {
+        if let Some(value_inner) = value.try_downcast::<IntegerValueInner>() {
+            Some(Number::Integer(value_inner.inner.clone()))
+        } else if let Some(value_inner) = value.try_downcast::<RationalValueInner>() {
+            Some(Number::Rational(value_inner.inner.clone()))
+        } else {
+            value
+                .try_downcast::<FloatingPointNumberValueInner>()
+                .map(|value_inner| Number::FloatingPoint(value_inner.inner))
+        }
+    }
+
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if let Ok(inner) = text.parse::<BigInt>() {
+            Some(Number::Integer(inner))
+        } else if let Ok(inner) = text.parse::<BigRational>() {
+            Some(Number::Rational(inner))
+        } else {
+            text.parse::<f64>().ok().map(Number::FloatingPoint)
+        }
+    }
+
+    pub fn into_value(self) -> Value {
+        match self {
+            Number::Integer(inner) => Value::new(IntegerValueInner { inner }),
+            Number::Rational(inner) if inner.is_integer() => Value::new(IntegerValueInner { inner: inner.to_integer() }),
+            Number::Rational(inner) => Value::new(RationalValueInner { inner }),
+            Number::FloatingPoint(inner) => Value::new(FloatingPointNumberValueInner { inner }),
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Number::Integer(inner) => inner.to_f64().unwrap_or(f64::NAN),
+            Number::Rational(inner) => inner.to_f64().unwrap_or(f64::NAN),
+            Number::FloatingPoint(inner) => *inner,
+        }
+    }
+
+    fn to_rational(&self) -> BigRational {
+        match self {
+            Number::Integer(inner) => BigRational::from_integer(inner.clone()),
+            Number::Rational(inner) => inner.clone(),
+            Number::FloatingPoint(_) => unreachable!(),
+        }
+    }
+
+    fn rank(&self) -> usize {
+        match self {
+            Number::Integer(_) => 0,
+            Number::Rational(_) => 1,
+            Number::FloatingPoint(_) => 2,
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Number::Integer(inner) => write!(f, "{}", inner),
+            Number::Rational(inner) => write!(f, "{}", inner),
+            Number::FloatingPoint(inner) => write!(f, "{}", inner),
+        }
+    }
+}
+
+fn unify(a: Number, b: Number) -> (Number, Number) {
+    match a.rank().max(b.rank()) {
+        0 => (a, b),
+        1 => (Number::Rational(a.to_rational()), Number::Rational(b.to_rational())),
+        _ => (Number::FloatingPoint(a.to_f64()), Number::FloatingPoint(b.to_f64())),
+    }
+}
+
+pub fn expect_number(value: &Value) -> Result<Number, EvaluationError> {
+    Number::from_value(value).ok_or_else(|| EvaluationError::new(value.clone(), EvaluationErrorReason::UnexpectedKind { expected: "Number" }))
+}
+
+pub fn add(a: Number, b: Number) -> Number {
+    match unify(a, b) {
+        (Number::Integer(a), Number::Integer(b)) => Number::Integer(a + b),
+        (Number::Rational(a), Number::Rational(b)) => Number::Rational(a + b),
+        (a, b) => Number::FloatingPoint(a.to_f64() + b.to_f64()),
+    }
+}
+
+pub fn subtract(a: Number, b: Number) -> Number {
+    match unify(a, b) {
+        (Number::Integer(a), Number::Integer(b)) => Number::Integer(a - b),
+        (Number::Rational(a), Number::Rational(b)) => Number::Rational(a - b),
+        (a, b) => Number::FloatingPoint(a.to_f64() - b.to_f64()),
+    }
+}
+
+pub fn multiply(a: Number, b: Number) -> Number {
+    match unify(a, b) {
+        (Number::Integer(a), Number::Integer(b)) => Number::Integer(a * b),
+        (Number::Rational(a), Number::Rational(b)) => Number::Rational(a * b),
+        (a, b) => Number::FloatingPoint(a.to_f64() * b.to_f64()),
+    }
+}
+
+pub fn divide(a: Number, b: Number) -> Option<Number> {
+    match unify(a, b) {
+        (Number::Integer(a), Number::Integer(b)) if !b.is_zero() => Some(Number::Rational(BigRational::new(a, b))),
+        (Number::Rational(a), Number::Rational(b)) if !b.is_zero() => Some(Number::Rational(a / b)),
+        (Number::FloatingPoint(a), Number::FloatingPoint(b)) => Some(Number::FloatingPoint(a / b)),
+        _ => None,
+    }
+}
+
+pub fn compare(a: Number, b: Number) -> Option<Ordering> {
+    match unify(a, b) {
+        (Number::Integer(a), Number::Integer(b)) => Some(a.cmp(&b)),
+        (Number::Rational(a), Number::Rational(b)) => Some(a.cmp(&b)),
+        (a, b) => a.to_f64().partial_cmp(&b.to_f64()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rational(numerator: i64, denominator: i64) -> Number {
+        Number::Rational(BigRational::new(numerator.into(), denominator.into()))
+    }
+
+    fn same(a: &Number, b: &Number) -> bool {
+        match (a, b) {
+            (Number::Integer(a), Number::Integer(b)) => a == b,
+            (Number::Rational(a), Number::Rational(b)) => a == b,
+            (Number::FloatingPoint(a), Number::FloatingPoint(b)) => a == b || (a.is_nan() && b.is_nan()),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn promotion() {
+        let one = Number::Integer(1.into());
+        assert!(same(&add(one.clone(), rational(1, 2)), &rational(3, 2)));
+        assert!(same(&multiply(rational(2, 3), Number::Integer(3.into())), &rational(2, 1)));
+        assert!(same(&subtract(rational(1, 2), Number::FloatingPoint(0.25)), &Number::FloatingPoint(0.25)));
+        assert!(same(&add(one, Number::FloatingPoint(0.5)), &Number::FloatingPoint(1.5)));
+        // Rationals that turn out to be integral are written back as integers.
+        assert!(add(rational(1, 2), rational(1, 2)).into_value().is::<IntegerValueInner>());
+        assert!(rational(1, 3).into_value().is::<RationalValueInner>());
+    }
+
+    #[test]
+    fn exact_division() {
+        // Integer division stays exact instead of truncating or rounding through a float.
+        let third = divide(Number::Integer(1.into()), Number::Integer(3.into())).unwrap();
+        assert!(same(&third, &rational(1, 3)));
+        let sum = add(add(third.clone(), third.clone()), third);
+        assert!(same(&sum, &rational(1, 1)));
+        assert!(same(&divide(Number::Integer(6.into()), Number::Integer(3.into())).unwrap(), &rational(2, 1)));
+        assert!(same(&Number::parse(" 10/4 ").unwrap(), &rational(5, 2)));
+        assert!(same(
+            &Number::parse("12345678901234567890123").unwrap(),
+            &Number::Integer("12345678901234567890123".parse().unwrap())
+        ));
+        assert!(Number::parse("ten").is_none());
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert!(divide(Number::Integer(1.into()), Number::Integer(0.into())).is_none());
+        assert!(divide(rational(1, 2), Number::Integer(0.into())).is_none());
+        // Floats follow IEEE 754 instead.
+        assert!(same(
+            &divide(Number::FloatingPoint(1.0), Number::Integer(0.into())).unwrap(),
+            &Number::FloatingPoint(f64::INFINITY)
+        ));
+        assert!(same(
+            &divide(Number::FloatingPoint(0.0), Number::FloatingPoint(0.0)).unwrap(),
+            &Number::FloatingPoint(f64::NAN)
+        ));
+    }
+
+    #[test]
+    fn comparison() {
+        assert_eq!(compare(rational(1, 3), Number::Integer(0.into())), Some(Ordering::Greater));
+        assert_eq!(compare(rational(1, 2), Number::FloatingPoint(0.5)), Some(Ordering::Equal));
+        assert_eq!(compare(Number::Integer(1.into()), Number::FloatingPoint(f64::NAN)), None);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::{data::*, number, number::Number, value_kind, Value};
+use std::cmp::Ordering;
+
+fn kind_rank(value: &Value) -> (usize, &'static str) {
+    if Number::from_value(value).is_some() {
+        (0, "Number")
+    } else if value.is::<StringValueInner>() {
+        (1, "String")
+    } else if value.is::<SymbolValueInner>() {
+        (2, "Symbol")
+    } else if value.is::<BooleanValueInner>() {
+        (3, "Boolean")
+    } else {
+        (4, kind_name(value))
+    }
+}
+
+fn number_rank(number: &Number) -> usize {
+    match number {
+        Number::Integer(_) => 0,
+        Number::Rational(_) => 1,
+        Number::FloatingPoint(_) => 2,
+    }
+}
+
+fn compare_numbers(a: Number, b: Number) -> Ordering {
+    let is_nan = |number: &Number| matches!(number, Number::FloatingPoint(inner) if inner.is_nan());
+    let ranks = number_rank(&a).cmp(&number_rank(&b));
+    let nans = is_nan(&a).cmp(&is_nan(&b));
+    match number::compare(a, b) {
+        Some(Ordering::Equal) => ranks,
+        Some(ordering) => ordering,
+        None => nans,
+    }
+}
+
+fn compare_leaves(a: &Value, b: &Value) -> Ordering {
+    if let (Some(a), Some(b)) = (Number::from_value(a), Number::from_value(b)) {
+        compare_numbers(a, b)
+    } else {
+        value_kind::kind_or_unregistered(a).compare_leaves(a, b)
+    }
+}
+
+pub fn compare(a: &Value, b: &Value) -> Ordering {
+    enum Task {
+        Compare(Value, Value),
+        Lengths(usize, usize),
+    }
+    let mut tasks = vec![Task::Compare(a.clone(), b.clone())];
+    while let Some(task) = tasks.pop() {
+        let ordering = match task {
+            Task::Compare(a, b) if a == b => Ordering::Equal,
+            Task::Compare(a, b) => {
+                let ordering = kind_rank(&a).cmp(&kind_rank(&b)).then_with(|| compare_leaves(&a, &b));
+                if ordering == Ordering::Equal {
+                    let a_parts = get_parts(a);
+                    let b_parts = get_parts(b);
+                    tasks.push(Task::Lengths(a_parts.len(), b_parts.len()));
+                    tasks.extend(a_parts.into_iter().zip(b_parts).rev().map(|(a, b)| Task::Compare(a, b)));
+                }
+                ordering
+            }
+            Task::Lengths(a, b) => a.cmp(&b),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+pub struct Ordered(pub Value);
+
+impl PartialEq for Ordered {
+    fn eq(&self, other: &Self) -> bool {
+        compare(&self.0, &other.0) == Ordering::Equal
+    }
+}
+
+impl Eq for Ordered {}
+
+impl PartialOrd for Ordered {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ordered {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare(&self.0, &other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::tests::*,
+        value::{Lock, Shared},
+    };
+    use std::collections::HashMap;
+
+    struct Unregistered;
+
+    fn string(inner: &str) -> Value {
+        Value::new(StringValueInner { inner: inner.to_owned() })
+    }
+
+    fn float(inner: f64) -> Value {
+        Value::new(FloatingPointNumberValueInner { inner })
+    }
+
+    fn sorted(values: &[Value]) -> Vec<Value> {
+        let mut ordered = values.iter().cloned().map(Ordered).collect::<Vec<_>>();
+        ordered.sort();
+        ordered.into_iter().map(|ordered| ordered.0).collect()
+    }
+
+    // Numbers come first by value, whatever their kind, followed by strings, symbols, booleans and other kinds by name.
+    #[test]
+    fn sort_mixed_values() {
+        let half = Value::new(RationalValueInner {
+            inner: num_rational::BigRational::new(1.into(), 2.into()),
+        });
+        let values = [
+            symbol("b"),
+            string("b"),
+            Value::new(BooleanValueInner { inner: false }),
+            float(f64::NAN),
+            integer(2),
+            float(1.0),
+            tuple(vec![integer(1)]),
+            string("a"),
+            half.clone(),
+            integer(1),
+            symbol("a"),
+        ];
+        let expected = [
+            half,
+            integer(1),
+            float(1.0),
+            integer(2),
+            float(f64::NAN),
+            string("a"),
+            string("b"),
+            symbol("a"),
+            symbol("b"),
+            Value::new(BooleanValueInner { inner: false }),
+            tuple(vec![integer(1)]),
+        ];
+        let sorted = sorted(&values);
+        assert_eq!(sorted.len(), expected.len());
+        for (value, expected) in sorted.iter().zip(&expected) {
+            assert!(crate::structural::structural_eq(value, expected), "{:?} is not {:?}", value, expected);
+        }
+    }
+
+    // Tuples are ordered by their parts first and their lengths second.
+    #[test]
+    fn sort_tuples() {
+        let values = [
+            tuple(vec![integer(2)]),
+            tuple(vec![integer(1), integer(5)]),
+            tuple(vec![integer(1)]),
+            tuple(vec![]),
+        ];
+        let sorted = sorted(&values);
+        for (value, expected) in sorted.iter().zip(&[&values[3], &values[2], &values[1], &values[0]]) {
+            assert!(value.ptr_eq(expected));
+        }
+    }
+
+    fn assert_total(a: &Value, b: &Value) {
+        let ordering = compare(a, b);
+        assert_ne!(ordering, Ordering::Equal);
+        assert_eq!(compare(b, a), ordering.reverse());
+        assert_eq!(compare(a, a), Ordering::Equal);
+    }
+
+    // Values that are only equal to themselves are still ordered against each other, consistently.
+    #[test]
+    fn opaque_values_are_ordered() {
+        assert_total(&Value::new(Unregistered), &Value::new(Unregistered));
+        let k = symbol("k");
+        let mut execution_context = ExecutionContext::new(HashMap::new());
+        let capture = || {
+            Value::new(ResetValueInner {
+                inner: Value::new(ShiftValueInner {
+                    handler: function(vec![k.clone()], dereference(&k)),
+                }),
+            })
+        };
+        let first = evaluate(&mut execution_context, capture()).unwrap();
+        let second = evaluate(&mut execution_context, capture()).unwrap();
+        assert_total(&first, &second);
+        let closure = |environment| {
+            Value::new(ClosureValueInner {
+                arguments: tuple(vec![]),
+                body: Value::new(HoldValueInner { inner: dereference(&k) }),
+                environment,
+            })
+        };
+        let environment = || {
+            Some(Shared::new(Lock::new(Environment {
+                values: vec![(k.clone(), integer(1))].into_iter().collect(),
+                parent: None,
+            })))
+        };
+        assert_total(&closure(environment()), &closure(environment()));
+        assert_total(&closure(None), &closure(environment()));
+        assert_eq!(compare(&closure(None), &closure(None)), Ordering::Equal);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{data::*, structural, Value};
+use std::collections::HashMap;
+
+pub type Bindings = HashMap<Value, Value>;
+
+fn bind(bindings: &mut Bindings, name: &Value, value: Value) -> bool {
+    match bindings.get(name) {
+        Some(bound) => equal(bound, &value),
+        None => {
+            bindings.insert(name.clone(), value);
+            true
+        }
+    }
+}
+
+fn as_repeated(pattern: &Value) -> Option<(Option<Value>, Value, usize)> {
+    if let Some(pattern_inner) = pattern.try_downcast::<RepeatedPatternValueInner>() {
+        Some((None, pattern_inner.inner.clone(), pattern_inner.minimum))
+    } else if let Some(pattern_inner) = pattern.try_downcast::<PatternValueInner>() {
+        let repeated = pattern_inner.inner.try_downcast::<RepeatedPatternValueInner>()?;
+        Some((Some(pattern_inner.name.clone()), repeated.inner.clone(), repeated.minimum))
+    } else {
+        None
+    }
+}
+
+fn match_sequence(patterns: &[Value], values: &[Value], bindings: &mut Bindings) -> bool {
+    let (first, rest) = match patterns.split_first() {
+        Some(split) => split,
+        None => return values.is_empty(),
+    };
+    if let Some((name, inner, minimum)) = as_repeated(first) {
+        for count in minimum..=values.len() {
+            let mut attempt = bindings.clone();
+            let matched = values[..count].iter().all(|value| match_value(&inner, value, &mut attempt))
+                && match &name {
+                    Some(name) => bind(
+                        &mut attempt,
+                        name,
+                        Value::new(TupleValueInner {
+                            inner: values[..count].to_vec(),
+                        }),
+                    ),
+                    None => true,
+                }
+                && match_sequence(rest, &values[count..], &mut attempt);
+            if matched {
+                *bindings = attempt;
+                return true;
+            }
+        }
+        false
+    } else {
+        let (value, values) = match values.split_first() {
+            Some(split) => split,
+            None => return false,
+        };
+        let mut attempt = bindings.clone();
+        if match_value(first, value, &mut attempt) && match_sequence(rest, values, &mut attempt) {
+            *bindings = attempt;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn match_value(pattern: &Value, value: &Value, bindings: &mut Bindings) -> bool {
+    if let Some(pattern_inner) = pattern.try_downcast::<BlankValueInner>() {
+        match &pattern_inner.kind {
+            Some(kind) => kind == kind_name(value),
+            None => true,
+        }
+    } else if let Some(pattern_inner) = pattern.try_downcast::<PatternValueInner>() {
+        match_value(&pattern_inner.inner, value, bindings) && bind(bindings, &pattern_inner.name, value.clone())
+    } else if pattern.is::<RepeatedPatternValueInner>() {
+        match_sequence(std::slice::from_ref(pattern), std::slice::from_ref(value), bindings)
+    } else if equal(pattern, value) {
+        true
+    } else if kind_name(pattern) != kind_name(value) || !structural::leaves_eq(pattern, value) {
+        false
+    } else {
+        match_sequence(&get_parts(pattern.clone()), &get_parts(value.clone()), bindings)
+    }
+}
+
+pub fn match_pattern(pattern: &Value, value: &Value) -> Option<Bindings> {
+    let mut bindings = Bindings::new();
+    if match_value(pattern, value, &mut bindings) {
+        Some(bindings)
+    } else {
+        None
+    }
+}
+
+pub fn substitute(value: Value, bindings: &Bindings) -> Value {
+    rewrite(value, |value| match bindings.get(&value) {
+        Some(bound) => Rewrite::Replace(bound.clone()),
+        None => Rewrite::Descend(value),
+    })
+}
+
+pub fn expect_rules(rules: &Value) -> Result<Vec<Value>, EvaluationError> {
+    let rules = match rules.try_downcast::<TupleValueInner>() {
+        Some(rules_inner) => rules_inner.inner.clone(),
+        None => vec![rules.clone()],
+    };
+    for rule in &rules {
+        expect_kind::<RuleValueInner>(rule, "Rule")?;
+    }
+    Ok(rules)
+}
+
+pub fn replace_all(value: Value, rules: &[Value]) -> Value {
+    rewrite(value, |value| {
+        for rule in rules {
+            let rule = rule.downcast::<RuleValueInner>();
+            if let Some(bindings) = match_pattern(&rule.left, &value) {
+                return Rewrite::Replace(substitute(rule.right.clone(), &bindings));
+            }
+        }
+        Rewrite::Descend(value)
+    })
+}
+
+pub fn replace_repeated(execution_context: &mut ExecutionContext, mut value: Value, rules: &[Value]) -> Result<Value, EvaluationError> {
+    loop {
+        execution_context.check_budget(&value)?;
+        let result = replace_all(value.clone(), rules);
+        // Rules rebuild the values they match, so a rule mapping a value to an equal one produces new nodes on every pass.
+        if structural::structural_eq(&result, &value) {
+            return Ok(result);
+        }
+        value = result;
+    }
+}
@@ -1,129 +1,56 @@
-use crate::{data::*, Value};
+use crate::{value_kind, value_kind::JsonValue, Value};
 use indexmap::map::IndexMap;
 use serde_json::json;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+};
 use uuid::Uuid;
 
-type JsonValue = serde_json::Value;
-type JsonNumber = serde_json::Number;
+#[derive(Debug)]
+pub enum SerializationError {
+    // A value of a kind without a serialized form, like a continuation, was reached while serializing.
+    UnsupportedKind { name: &'static str },
+    // An entry names a kind that is not registered or has no serialized form.
+    UnknownKind { name: String },
+    // A value refers to itself, which only a closure bound in its own environment can do.
+    Cyclic,
+    Malformed,
+}
 
-fn serialize_one<F: FnMut(Value) -> JsonValue>(value: Value, mut f: F) -> JsonValue {
-    if let Some(value_inner) = value.try_downcast::<HoldValueInner>() {
-        json!({
-            "type": "Hold",
-            "inner": f(value_inner.inner.clone()),
-        })
-    } else if let Some(value_inner) = value.try_downcast::<ReleaseValueInner>() {
-        json!({
-            "type": "Release",
-            "inner": f(value_inner.inner.clone()),
-        })
-    } else if let Some(value_inner) = value.try_downcast::<AssignmentValueInner>() {
-        json!({
-            "type": "Assignment",
-            "source": f(value_inner.source.clone()),
-            "target": f(value_inner.target.clone()),
-        })
-    } else if let Some(value_inner) = value.try_downcast::<DereferenceValueInner>() {
-        json!({
-            "type": "Dereference",
-            "inner": f(value_inner.inner.clone()),
-        })
-    } else if let Some(value_inner) = value.try_downcast::<ExecutableSequenceValueInner>() {
-        json!({
-            "type": "ExecutableSequence",
-            "inner": JsonValue::Array(value_inner.inner.iter().cloned().map(&mut f).collect()),
-        })
-    } else if let Some(value_inner) = value.try_downcast::<ExecutableFunctionValueInner>() {
-        json!({
-            "type": "ExecutableFunction",
-            "arguments": f(value_inner.arguments.clone()),
-            "body": f(value_inner.body.clone()),
-        })
-    } else if let Some(value_inner) = value.try_downcast::<FunctionApplicationValueInner>() {
-        json!({
-            "type": "FunctionApplication",
-            "function": f(value_inner.function.clone()),
-            "arguments": f(value_inner.arguments.clone()),
-        })
-    } else if let Some(value_inner) = value.try_downcast::<IntrinsicCallValueInner>() {
-        json!({
-            "type": "IntrinsicCall",
-            "intrinsic": f(value_inner.intrinsic.clone()),
-            "arguments": f(value_inner.arguments.clone()),
-        })
-    } else if let Some(value_inner) = value.try_downcast::<TupleValueInner>() {
-        json!({
-            "type": "Tuple",
-            "inner": JsonValue::Array(value_inner.inner.iter().cloned().map(&mut f).collect()),
-        })
-    } else if let Some(_) = value.try_downcast::<NullValueInner>() {
-        json!({
-            "type": "Null",
-        })
-    } else if let Some(value_inner) = value.try_downcast::<SymbolValueInner>() {
-        json!({
-            "type": "Symbol",
-            "name": JsonValue::String(value_inner.name.clone()),
-        })
-    } else if let Some(value_inner) = value.try_downcast::<FloatingPointNumberValueInner>() {
-        json!({
-            "type": "FloatingPointNumber",
-            "inner": JsonValue::Number(JsonNumber::from_f64(value_inner.inner).unwrap()),
-        })
-    } else {
-        unreachable!()
+impl fmt::Display for SerializationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SerializationError::UnsupportedKind { name } => write!(f, "values of kind {} cannot be serialized", name),
+            SerializationError::UnknownKind { name } => write!(f, "values of kind {} cannot be deserialized", name),
+            SerializationError::Cyclic => write!(f, "the serialized value contains itself"),
+            SerializationError::Malformed => write!(f, "the serialized value is malformed"),
+        }
     }
 }
 
-fn deserialize_one<F: FnMut(JsonValue) -> Value>(entry: &JsonValue, mut f: F) -> Value {
-    match entry["type"].as_str().unwrap() {
-        "Hold" => Value::new(HoldValueInner {
-            inner: f(entry["inner"].clone()),
-        }),
-        "Release" => Value::new(ReleaseValueInner {
-            inner: f(entry["inner"].clone()),
-        }),
-        "Assignment" => Value::new(AssignmentValueInner {
-            source: f(entry["source"].clone()),
-            target: f(entry["target"].clone()),
-        }),
-        "Dereference" => Value::new(DereferenceValueInner {
-            inner: f(entry["inner"].clone()),
-        }),
-        "ExecutableSequence" => Value::new(ExecutableSequenceValueInner {
-            inner: entry["inner"].clone().as_array().unwrap().iter().cloned().map(&mut f).collect(),
-        }),
-        "ExecutableFunction" => Value::new(ExecutableFunctionValueInner {
-            arguments: f(entry["arguments"].clone()),
-            body: f(entry["body"].clone()),
-        }),
-        "FunctionApplication" => Value::new(FunctionApplicationValueInner {
-            function: f(entry["function"].clone()),
-            arguments: f(entry["arguments"].clone()),
-        }),
-        "IntrinsicCall" => Value::new(IntrinsicCallValueInner {
-            intrinsic: f(entry["intrinsic"].clone()),
-            arguments: f(entry["arguments"].clone()),
-        }),
-        "Tuple" => Value::new(TupleValueInner {
-            inner: entry["inner"].clone().as_array().unwrap().iter().cloned().map(&mut f).collect(),
-        }),
-        "Null" => Value::new(NullValueInner),
-        "Symbol" => Value::new(SymbolValueInner {
-            name: entry["name"].as_str().unwrap().to_owned(),
-        }),
-        "FloatingPointNumber" => Value::new(FloatingPointNumberValueInner {
-            inner: entry["inner"].as_f64().unwrap(),
-        }),
-        _ => unreachable!(),
+fn serialize_one<F: FnMut(Value) -> JsonValue>(value: Value, mut f: F) -> JsonValue {
+    let kind = value_kind::kind_or_unregistered(&value);
+    let mut entry = json!({
+        "type": kind.name(),
+    });
+    entry.as_object_mut().unwrap().extend(kind.serialize(&value, &mut f));
+    entry
+}
+
+fn deserialize_one<F: FnMut(JsonValue) -> Result<Value, SerializationError>>(entry: &JsonValue, mut f: F) -> Result<Value, SerializationError> {
+    let name = entry["type"].as_str().ok_or(SerializationError::Malformed)?;
+    match value_kind::kind_named(name) {
+        Some(kind) if kind.is_serializable() => kind.deserialize(entry, &mut f),
+        _ => Err(SerializationError::UnknownKind { name: name.to_owned() }),
     }
 }
 
+// For display only: values of kinds without a serialized form are written as just their kind.
 pub fn serialize_readable(value: Value) -> String {
     fn f(value: Value) -> JsonValue {
         serialize_one(value, f)
-    };
+    }
     serde_json::to_string_pretty(&f(value)).unwrap()
 }
 
@@ -145,17 +72,21 @@ fn serialize_id(id: Uuid) -> JsonValue {
     JsonValue::String(format!("{:X}", id.to_simple()))
 }
 
-fn deserialize_id(id: &JsonValue) -> Uuid {
-    Uuid::parse_str(id.as_str().unwrap()).unwrap()
+fn deserialize_id(id: &JsonValue) -> Result<Uuid, SerializationError> {
+    id.as_str().and_then(|id| Uuid::parse_str(id).ok()).ok_or(SerializationError::Malformed)
 }
 
-pub fn serialize(serialization_storage: &mut SerializationStorage, input_value: Value) -> String {
+pub fn serialize(serialization_storage: &mut SerializationStorage, input_value: Value) -> Result<String, SerializationError> {
     let mut done = HashSet::new();
     let mut queue = VecDeque::new();
     queue.push_back(input_value.clone());
     let mut unordered = HashMap::new();
     while !queue.is_empty() {
         let current = queue.pop_front().unwrap();
+        let kind = value_kind::kind_or_unregistered(&current);
+        if !kind.is_serializable() {
+            return Err(SerializationError::UnsupportedKind { name: kind.name() });
+        }
         done.insert(current.clone());
         let mut f = |value| {
             if !done.contains(&value) {
@@ -169,38 +100,183 @@ pub fn serialize(serialization_storage: &mut SerializationStorage, input_value:
     }
     let mut ordered = unordered.into_iter().collect::<Vec<_>>();
     ordered.sort_by_key(|(value, _)| serialization_storage.known_ids.get_index_of(value).unwrap());
-    serde_json::to_string_pretty(&json!({
+    Ok(serde_json::to_string_pretty(&json!({
         "id": serialize_id(serialization_storage.known_ids[&input_value]),
         "values": JsonValue::Array(ordered.into_iter().map(|(_, entry)| entry).collect()),
     }))
-    .unwrap()
+    .unwrap())
 }
 
-pub fn deserialize(mut serialization_storage: &mut SerializationStorage, input_str: &str) -> Value {
-    let parsed: JsonValue = serde_json::from_str(input_str).unwrap();
-    let mut deserialized = HashMap::new();
-    fn f(serialization_storage: &mut SerializationStorage, deserialized: &mut HashMap<Uuid, Value>, entry: &JsonValue) -> Value {
-        let id = deserialize_id(&entry["id"]);
+pub fn deserialize(serialization_storage: &mut SerializationStorage, input_str: &str) -> Result<Value, SerializationError> {
+    let parsed: JsonValue = serde_json::from_str(input_str).map_err(|_| SerializationError::Malformed)?;
+    let mut entries = HashMap::new();
+    for entry in parsed["values"].as_array().ok_or(SerializationError::Malformed)? {
+        entries.insert(deserialize_id(&entry["id"])?, entry);
+    }
+    // Reads the value with the given id after its parts. Values already known to the storage are reused, and an entry reached
+    // again while its own parts are being read is reported, since it could only be built from itself.
+    fn f(
+        serialization_storage: &mut SerializationStorage,
+        entries: &HashMap<Uuid, &JsonValue>,
+        reading: &mut HashSet<Uuid>,
+        id: Uuid,
+    ) -> Result<Value, SerializationError> {
         if let Some(value) = serialization_storage.known_value.get(&id) {
-            value.clone()
-        } else {
-            let value = deserialize_one(entry, |entry| f(serialization_storage, deserialized, &entry));
-            if serialization_storage.known_ids.contains_key(&value) {
-                serialization_storage.known_ids.shift_remove(&value);
-            }
-            serialization_storage.known_ids.insert(value.clone(), id);
-            serialization_storage.known_value.insert(id, value.clone());
-            value
+            return Ok(value.clone());
+        }
+        let entry = entries.get(&id).ok_or(SerializationError::Malformed)?;
+        if !reading.insert(id) {
+            return Err(SerializationError::Cyclic);
+        }
+        let value = deserialize_one(entry, |part| f(serialization_storage, entries, reading, deserialize_id(&part)?))?;
+        reading.remove(&id);
+        if serialization_storage.known_ids.contains_key(&value) {
+            serialization_storage.known_ids.shift_remove(&value);
         }
+        serialization_storage.known_ids.insert(value.clone(), id);
+        serialization_storage.known_value.insert(id, value.clone());
+        Ok(value)
     }
-    f(
-        &mut serialization_storage,
-        &mut deserialized,
-        parsed["values"]
-            .as_array()
+    f(serialization_storage, &entries, &mut HashSet::new(), deserialize_id(&parsed["id"])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{tests::*, *},
+        value::{Lock, Shared},
+    };
+
+    struct Unregistered;
+
+    #[test]
+    fn serialize_closure_in_its_own_environment() {
+        let f = symbol("f");
+        let environment = Shared::new(Lock::new(Environment {
+            values: HashMap::new(),
+            parent: None,
+        }));
+        let closure = Value::new(ClosureValueInner {
+            arguments: tuple(vec![]),
+            body: Value::new(HoldValueInner { inner: dereference(&f) }),
+            environment: Some(environment.clone()),
+        });
+        environment.borrow_mut().values.insert(f, closure.clone());
+        let serialized = serialize_readable(closure.clone());
+        assert!(serialized.contains("Closure"));
+        let serialized = serialize(&mut SerializationStorage::new(), closure).unwrap();
+        let error = deserialize(&mut SerializationStorage::new(), &serialized).unwrap_err();
+        assert!(matches!(error, SerializationError::Cyclic));
+        // The closure and its environment refer to each other, so they are never freed otherwise.
+        environment.borrow_mut().values.clear();
+    }
+
+    #[test]
+    fn round_trip_through_fresh_storage() {
+        let (x, y, outer, g) = (symbol("x"), symbol("y"), symbol("outer"), symbol("g"));
+        let mut execution_context = ExecutionContext::new(HashMap::new());
+        let program = sequence(vec![
+            assign(&outer, function(vec![y.clone()], function(vec![x.clone()], dereference(&y)))),
+            apply(&outer, vec![integer(5)]),
+        ]);
+        let closure = evaluate(&mut execution_context, program).unwrap();
+        let shared = tuple(vec![integer(1), Value::new(StringValueInner { inner: "text".to_owned() })]);
+        let value = tuple(vec![
+            shared.clone(),
+            shared,
+            closure,
+            Value::new(RationalValueInner {
+                inner: num_rational::BigRational::new(2.into(), 3.into()),
+            }),
+            Value::new(AssociationValueInner {
+                inner: vec![(x, Value::new(BooleanValueInner { inner: true }))].into_iter().collect(),
+            }),
+        ]);
+        let serialized = serialize(&mut SerializationStorage::new(), value.clone()).unwrap();
+        let deserialized = deserialize(&mut SerializationStorage::new(), &serialized).unwrap();
+        assert!(crate::structural::structural_eq(&deserialized, &value));
+        // Parts shared in the serialized value are shared again, and the closure still sees its environment.
+        let parts = get_parts(deserialized);
+        assert!(parts[0].ptr_eq(&parts[1]));
+        execution_context.assign(g.clone(), parts[2].clone());
+        let result = evaluate(&mut execution_context, apply(&g, vec![integer(0)])).unwrap();
+        assert!(crate::structural::structural_eq(&result, &integer(5)));
+    }
+
+    #[test]
+    fn malformed_entries() {
+        let serialized = serialize(&mut SerializationStorage::new(), tuple(vec![integer(1)])).unwrap();
+        let mut parsed: JsonValue = serde_json::from_str(&serialized).unwrap();
+        let integer_entry = parsed["values"]
+            .as_array_mut()
             .unwrap()
-            .iter()
-            .find(|entry| deserialize_id(&entry["id"]) == deserialize_id(&parsed["id"]))
-            .unwrap(),
-    )
+            .iter_mut()
+            .find(|entry| entry["type"] == "Integer")
+            .unwrap();
+        integer_entry["inner"] = json!("one");
+        let error = deserialize(&mut SerializationStorage::new(), &parsed.to_string()).unwrap_err();
+        assert!(matches!(error, SerializationError::Malformed));
+        for input in &["", "[]", r#"{ "id": "0", "values": [] }"#, r#"{ "id": 1, "values": [{ "id": 1 }] }"#] {
+            let error = deserialize(&mut SerializationStorage::new(), input).unwrap_err();
+            assert!(matches!(error, SerializationError::Malformed));
+        }
+    }
+
+    #[test]
+    fn non_finite_floats() {
+        for inner in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY, -0.5] {
+            let value = Value::new(FloatingPointNumberValueInner { inner });
+            assert!(serialize_readable(value.clone()).contains("FloatingPointNumber"));
+            let serialized = serialize(&mut SerializationStorage::new(), value.clone()).unwrap();
+            let deserialized = deserialize(&mut SerializationStorage::new(), &serialized).unwrap();
+            assert!(crate::structural::structural_eq(&deserialized, &value));
+        }
+        let serialized = serialize(&mut SerializationStorage::new(), Value::new(FloatingPointNumberValueInner { inner: 1.0 })).unwrap();
+        let mut parsed: JsonValue = serde_json::from_str(&serialized).unwrap();
+        // Only the non-finite values are read back from names.
+        for name in &["1.5", "float"] {
+            parsed["values"][0]["inner"] = json!(name);
+            let error = deserialize(&mut SerializationStorage::new(), &parsed.to_string()).unwrap_err();
+            assert!(matches!(error, SerializationError::Malformed));
+        }
+    }
+
+    #[test]
+    fn zero_denominator() {
+        let value = Value::new(RationalValueInner {
+            inner: num_rational::BigRational::new(1.into(), 2.into()),
+        });
+        let serialized = serialize(&mut SerializationStorage::new(), value).unwrap();
+        let mut parsed: JsonValue = serde_json::from_str(&serialized).unwrap();
+        parsed["values"][0]["denominator"] = json!("0");
+        let error = deserialize(&mut SerializationStorage::new(), &parsed.to_string()).unwrap_err();
+        assert!(matches!(error, SerializationError::Malformed));
+    }
+
+    // Continuations and values of unregistered kinds are reported instead of being written, or read back from a hand-written entry.
+    #[test]
+    fn unsupported_kinds_are_not_serialized() {
+        let k = symbol("k");
+        let mut execution_context = ExecutionContext::new(HashMap::new());
+        let program = Value::new(ResetValueInner {
+            inner: Value::new(ShiftValueInner {
+                handler: function(vec![k.clone()], dereference(&k)),
+            }),
+        });
+        let continuation = evaluate(&mut execution_context, program).unwrap();
+        assert!(continuation.is::<ContinuationValueInner>());
+        let mut serialization_storage = SerializationStorage::new();
+        for value in [tuple(vec![integer(1), continuation]), Value::new(Unregistered)] {
+            assert!(serialize_readable(value.clone()).contains("\"type\""));
+            let error = serialize(&mut serialization_storage, value).unwrap_err();
+            assert!(matches!(error, SerializationError::UnsupportedKind { .. }));
+        }
+        let mut serialization_storage = SerializationStorage::new();
+        let entry = r#"{ "id": "7B2C6B3C8E4A4F0C9D1E2F3A4B5C6D7E", "values": [{ "id": "7B2C6B3C8E4A4F0C9D1E2F3A4B5C6D7E", "type": "Continuation" }] }"#;
+        let error = deserialize(&mut serialization_storage, entry).unwrap_err();
+        assert!(matches!(error, SerializationError::UnknownKind { name } if name == "Continuation"));
+        let error = deserialize(&mut serialization_storage, "{}").unwrap_err();
+        assert!(matches!(error, SerializationError::Malformed));
+    }
 }
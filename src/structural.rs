@@ -0,0 +1,78 @@
+use crate::{data::*, value_kind, Value};
+use std::hash::Hasher;
+
+pub fn leaves_eq(a: &Value, b: &Value) -> bool {
+    value_kind::kind_or_unregistered(a).leaves_eq(a, b)
+}
+
+pub fn structural_eq(a: &Value, b: &Value) -> bool {
+    let mut pairs = vec![(a.clone(), b.clone())];
+    while let Some((a, b)) = pairs.pop() {
+        if a.ptr_eq(&b) {
+            continue;
+        }
+        if kind_name(&a) != kind_name(&b) || !leaves_eq(&a, &b) {
+            return false;
+        }
+        let a_parts = get_parts(a);
+        let b_parts = get_parts(b);
+        if a_parts.len() != b_parts.len() {
+            return false;
+        }
+        pairs.extend(a_parts.into_iter().zip(b_parts));
+    }
+    true
+}
+
+// 64-bit FNV-1a, chosen over `DefaultHasher` because its output is specified and therefore the same in every process.
+struct StableHasher {
+    state: u64,
+}
+
+impl StableHasher {
+    fn new() -> Self {
+        StableHasher { state: 0xcbf2_9ce4_8422_2325 }
+    }
+
+    fn write_str(&mut self, text: &str) {
+        self.write(&(text.len() as u64).to_le_bytes());
+        self.write(text.as_bytes());
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= u64::from(*byte);
+            self.state = self.state.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.state
+    }
+}
+
+fn hash_leaf(hasher: &mut StableHasher, value: &Value) {
+    value_kind::kind_or_unregistered(value).hash_leaf(value, hasher);
+}
+
+pub fn leaf_hash(value: &Value) -> u64 {
+    let mut hasher = StableHasher::new();
+    hasher.write_str(kind_name(value));
+    hash_leaf(&mut hasher, value);
+    hasher.state
+}
+
+pub fn structural_hash(value: &Value) -> u64 {
+    let mut hasher = StableHasher::new();
+    let mut values = vec![value.clone()];
+    while let Some(value) = values.pop() {
+        hasher.write_str(kind_name(&value));
+        hash_leaf(&mut hasher, &value);
+        let parts = get_parts(value);
+        hasher.write(&(parts.len() as u64).to_le_bytes());
+        values.extend(parts.into_iter().rev());
+    }
+    hasher.state
+}
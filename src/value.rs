@@ -1,35 +1,188 @@
+use crate::data::SymbolValueInner;
 use std::{
-    any::Any,
+    any::TypeId,
+    cell::RefCell,
+    fmt,
     hash::{Hash, Hasher},
-    rc::Rc,
+    mem::ManuallyDrop,
 };
 
+// With the `sync` feature values are reference counted atomically and environments are guarded by a mutex, so that values and
+// execution contexts can be moved to and shared between threads. Otherwise the cheaper single-threaded counterparts are used.
+#[cfg(not(feature = "sync"))]
+mod sharing {
+    use std::any::Any;
+
+    pub use std::rc::{Rc as Shared, Weak};
+
+    pub type DynAny = dyn Any;
+
+    pub type Lock<T> = std::cell::RefCell<T>;
+
+    pub trait MaybeSend {}
+
+    impl<T: ?Sized> MaybeSend for T {}
+
+    pub trait MaybeSendSync {}
+
+    impl<T: ?Sized> MaybeSendSync for T {}
+}
+
+#[cfg(feature = "sync")]
+mod sharing {
+    use std::{
+        any::Any,
+        sync::{Mutex, MutexGuard},
+    };
+
+    pub use std::sync::{Arc as Shared, Weak};
+
+    pub type DynAny = dyn Any + Send + Sync;
+
+    pub struct Lock<T>(Mutex<T>);
+
+    impl<T> Lock<T> {
+        pub fn new(value: T) -> Self {
+            Lock(Mutex::new(value))
+        }
+
+        pub fn borrow(&self) -> MutexGuard<'_, T> {
+            self.0.lock().unwrap()
+        }
+
+        pub fn borrow_mut(&self) -> MutexGuard<'_, T> {
+            self.0.lock().unwrap()
+        }
+    }
+
+    pub trait MaybeSend: Send {}
+
+    impl<T: ?Sized + Send> MaybeSend for T {}
+
+    pub trait MaybeSendSync: Send + Sync {}
+
+    impl<T: ?Sized + Send + Sync> MaybeSendSync for T {}
+}
+
+use sharing::DynAny;
+pub use sharing::{Lock, MaybeSend, MaybeSendSync, Shared, Weak};
+
+thread_local! {
+    static PENDING_DROPS: RefCell<Option<Vec<Shared<DynAny>>>> = RefCell::new(None);
+}
+
 #[derive(Clone)]
 pub struct Value {
-    inner: Rc<dyn Any>,
+    inner: ManuallyDrop<Shared<DynAny>>,
 }
 
 impl Value {
-    pub fn new<T: 'static>(value_inner: T) -> Self {
-        Value { inner: Rc::new(value_inner) }
+    pub fn new<T: MaybeSendSync + 'static>(value_inner: T) -> Self {
+        Value {
+            inner: ManuallyDrop::new(Shared::new(value_inner)),
+        }
     }
 
     pub fn is<T: 'static>(&self) -> bool {
         self.inner.is::<T>()
     }
 
-    pub fn downcast<T: 'static>(&self) -> Rc<T> {
-        self.inner.clone().downcast().unwrap()
+    pub fn downcast<T: MaybeSendSync + 'static>(&self) -> Shared<T> {
+        Shared::clone(&self.inner).downcast().unwrap()
     }
 
-    pub fn try_downcast<T: 'static>(&self) -> Option<Rc<T>> {
-        self.inner.clone().downcast().ok()
+    pub fn try_downcast<T: MaybeSendSync + 'static>(&self) -> Option<Shared<T>> {
+        Shared::clone(&self.inner).downcast().ok()
+    }
+
+    pub fn inner_type_id(&self) -> TypeId {
+        (**self.inner).type_id()
+    }
+
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        (**self.inner).type_id() == (**other.inner).type_id() && Shared::as_ptr(&self.inner) as *const u8 == Shared::as_ptr(&other.inner) as *const u8
+    }
+
+    // Only meaningful while the value is alive, after which the address may be reused.
+    pub fn address(&self) -> usize {
+        Shared::as_ptr(&self.inner) as *const u8 as usize
+    }
+
+    pub fn downgrade(&self) -> WeakValue {
+        WeakValue {
+            inner: Shared::downgrade(&self.inner),
+        }
+    }
+
+    fn symbol_name(&self) -> Option<&str> {
+        self.inner
+            .downcast_ref::<SymbolValueInner>()
+            .filter(|symbol| !symbol.fresh)
+            .map(|symbol| symbol.name.as_str())
+    }
+}
+
+#[derive(Clone)]
+pub struct WeakValue {
+    inner: Weak<DynAny>,
+}
+
+impl WeakValue {
+    pub fn upgrade(&self) -> Option<Value> {
+        Some(Value {
+            inner: ManuallyDrop::new(self.inner.upgrade()?),
+        })
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.inner.strong_count() == 0
+    }
+}
+
+impl Drop for Value {
+    // Dropping the last reference to a deeply nested value would otherwise recurse once per level, so nested drops are queued and
+    // released iteratively by the outermost one.
+    fn drop(&mut self) {
+        // SAFETY: `inner` is never used again after this point.
+        let inner = unsafe { ManuallyDrop::take(&mut self.inner) };
+        if Shared::strong_count(&inner) > 1 {
+            return;
+        }
+        let result = PENDING_DROPS.try_with(|pending_drops| {
+            if let Some(pending_drops) = pending_drops.borrow_mut().as_mut() {
+                pending_drops.push(inner);
+                return;
+            }
+            *pending_drops.borrow_mut() = Some(Vec::new());
+            drop(inner);
+            loop {
+                let next = pending_drops.borrow_mut().as_mut().unwrap().pop();
+                match next {
+                    Some(next) => drop(next),
+                    None => break,
+                }
+            }
+            *pending_drops.borrow_mut() = None;
+        });
+        result.ok();
+    }
+}
+
+// Symbols are identified by their name, so that separately created symbols with the same name refer to the same variable.
+// Every other value is identified by its allocation.
+// Values are shown in their readable serialized form, so that they can appear in assertions and error messages.
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&crate::serialization::serialize_readable(self.clone()))
     }
 }
 
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
-        self.inner.type_id() == other.inner.type_id() && &*self.inner as *const dyn Any as *const u8 == &*other.inner as *const dyn Any as *const u8
+        match (self.symbol_name(), other.symbol_name()) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.ptr_eq(other),
+        }
     }
 }
 
@@ -37,7 +190,10 @@ impl Eq for Value {}
 
 impl Hash for Value {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.inner.type_id().hash(state);
-        (&*self.inner as *const dyn Any as *const u8).hash(state);
+        (**self.inner).type_id().hash(state);
+        match self.symbol_name() {
+            Some(name) => name.hash(state),
+            None => (Shared::as_ptr(&self.inner) as *const u8).hash(state),
+        }
     }
 }
@@ -0,0 +1,314 @@
+use crate::{data::*, kinds, serialization::SerializationError, value::MaybeSendSync, value::Shared, Value};
+use once_cell::sync::Lazy;
+use std::{
+    any::TypeId,
+    cell::RefCell,
+    cmp::Ordering,
+    collections::HashMap,
+    hash::Hasher,
+    sync::{atomic, atomic::AtomicUsize, RwLock},
+};
+
+pub type JsonValue = serde_json::Value;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum ComponentsLayout {
+    Top,
+    Middle,
+    Bottom,
+    Left,
+    Center,
+    Right,
+}
+
+// A description of how a value is drawn, independent of the backend that draws it.
+#[derive(Clone)]
+pub enum Rendering {
+    Empty {
+        width: f64,
+        height: f64,
+    },
+    Text(String),
+    Part(usize),
+    Underline {
+        inner: Box<Rendering>,
+        red: f64,
+        green: f64,
+        blue: f64,
+        alpha: f64,
+    },
+    Components {
+        layout: ComponentsLayout,
+        components: Vec<Rendering>,
+    },
+}
+
+impl Rendering {
+    pub fn text(text: &str) -> Self {
+        Rendering::Text(text.to_owned())
+    }
+
+    pub fn components(layout: ComponentsLayout, components: Vec<Rendering>) -> Self {
+        Rendering::Components { layout, components }
+    }
+}
+
+// What evaluating a value does next, as returned by `ValueKind::evaluate`. Evaluation never recurses on the Rust stack, so a
+// kind that needs the value of one of its parts asks for it to be evaluated and continues in `Resume::resume` with the result.
+#[allow(dead_code)]
+pub enum Step {
+    // The value evaluates to the given one, which is evaluated further until it is a fixpoint.
+    Return(Value),
+    // The value evaluates to the given one, which is known to be a fixpoint already.
+    ReturnSettled(Value),
+    EvaluateThen(Value, Shared<dyn Resume>),
+    Throw { tag: Value, value: Value },
+}
+
+// A resumption may run more than once, as it can be captured by a continuation.
+pub trait Resume: MaybeSendSync {
+    fn resume(&self, execution_context: &mut ExecutionContext, result: Value) -> Result<Step, EvaluationError>;
+}
+
+// Everything the rest of the crate needs to know about a kind of value. Control kinds such as `Release` or `FunctionApplication`
+// are evaluated by the machine in `data.rs` itself, since they manipulate its frames; `evaluate` is consulted for every other kind.
+#[allow(unused_variables)]
+pub trait ValueKind: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn inner_type_id(&self) -> TypeId;
+
+    fn parts(&self, value: &Value) -> Vec<Value> {
+        Vec::new()
+    }
+
+    fn replace_parts(&self, value: Value, parts: &[Value]) -> Value {
+        assert!(parts.is_empty());
+        value
+    }
+
+    fn evaluate(&self, execution_context: &mut ExecutionContext, value: &Value) -> Result<Step, EvaluationError> {
+        Ok(Step::Return(value.clone()))
+    }
+
+    // Kinds without a serialized form are reported by `serialization::serialize` and `serialization::deserialize` instead of
+    // being written or read, so their `deserialize` is never called.
+    fn is_serializable(&self) -> bool {
+        true
+    }
+
+    fn serialize(&self, value: &Value, f: &mut dyn FnMut(Value) -> JsonValue) -> serde_json::Map<String, JsonValue>;
+
+    // Reads a value back from its entry, with `f` reading each part from what `serialize` wrote for it. Malformed entries are
+    // reported rather than trusted.
+    fn deserialize(&self, entry: &JsonValue, f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError>;
+
+    fn render(&self, value: &Value) -> Rendering;
+
+    fn leaves_eq(&self, a: &Value, b: &Value) -> bool {
+        true
+    }
+
+    fn hash_leaf(&self, value: &Value, hasher: &mut dyn Hasher) {}
+
+    fn compare_leaves(&self, a: &Value, b: &Value) -> Ordering {
+        Ordering::Equal
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    by_type_id: HashMap<TypeId, &'static dyn ValueKind>,
+    by_name: HashMap<&'static str, &'static dyn ValueKind>,
+}
+
+impl Registry {
+    // Kinds live for the rest of the program, so lookups can hand out plain references.
+    fn insert(&mut self, kind: Box<dyn ValueKind>) {
+        let kind: &'static dyn ValueKind = Box::leak(kind);
+        self.by_type_id.insert(kind.inner_type_id(), kind);
+        self.by_name.insert(kind.name(), kind);
+    }
+}
+
+static REGISTRY: Lazy<RwLock<Registry>> = Lazy::new(|| {
+    let mut registry = Registry::default();
+    for kind in kinds::builtin_kinds() {
+        registry.insert(kind);
+    }
+    RwLock::new(registry)
+});
+
+// Incremented by every registration, which invalidates the caches of all threads.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+// The kinds looked up by one thread, so that most lookups take neither the lock of the registry nor a reference count.
+#[derive(Default)]
+struct Cache {
+    generation: usize,
+    by_type_id: HashMap<TypeId, Option<&'static dyn ValueKind>>,
+}
+
+thread_local! {
+    static CACHE: RefCell<Cache> = RefCell::new(Cache::default());
+}
+
+#[allow(dead_code)]
+pub fn register(kind: Box<dyn ValueKind>) {
+    let mut registry = REGISTRY.write().unwrap();
+    registry.insert(kind);
+    GENERATION.fetch_add(1, atomic::Ordering::Release);
+}
+
+pub fn kind_of(value: &Value) -> Option<&'static dyn ValueKind> {
+    let type_id = value.inner_type_id();
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let generation = GENERATION.load(atomic::Ordering::Acquire);
+        if cache.generation != generation {
+            *cache = Cache {
+                generation,
+                by_type_id: HashMap::new(),
+            };
+        }
+        *cache
+            .by_type_id
+            .entry(type_id)
+            .or_insert_with(|| REGISTRY.read().unwrap().by_type_id.get(&type_id).copied())
+    })
+}
+
+pub fn kind_named(name: &str) -> Option<&'static dyn ValueKind> {
+    REGISTRY.read().unwrap().by_name.get(name).copied()
+}
+
+// Values of kinds that were never registered are treated as opaque leaves, identified by the node.
+struct UnregisteredKind;
+
+impl ValueKind for UnregisteredKind {
+    fn name(&self) -> &'static str {
+        "Unregistered"
+    }
+
+    fn inner_type_id(&self) -> TypeId {
+        TypeId::of::<UnregisteredKind>()
+    }
+
+    fn is_serializable(&self) -> bool {
+        false
+    }
+
+    fn serialize(&self, _value: &Value, _f: &mut dyn FnMut(Value) -> JsonValue) -> serde_json::Map<String, JsonValue> {
+        serde_json::Map::new()
+    }
+
+    fn deserialize(&self, _entry: &JsonValue, _f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+        unreachable!()
+    }
+
+    fn render(&self, _value: &Value) -> Rendering {
+        Rendering::text("?")
+    }
+
+    fn leaves_eq(&self, a: &Value, b: &Value) -> bool {
+        a.ptr_eq(b)
+    }
+
+    fn compare_leaves(&self, a: &Value, b: &Value) -> Ordering {
+        a.address().cmp(&b.address())
+    }
+}
+
+pub fn kind_or_unregistered(value: &Value) -> &'static dyn ValueKind {
+    kind_of(value).unwrap_or(&UnregisteredKind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::tests::*,
+        number,
+        serialization::{self, SerializationStorage},
+        structural,
+    };
+
+    struct Twice {
+        inner: Value,
+    }
+
+    struct TwiceKind;
+
+    impl ValueKind for TwiceKind {
+        fn name(&self) -> &'static str {
+            "Twice"
+        }
+
+        fn inner_type_id(&self) -> TypeId {
+            TypeId::of::<Twice>()
+        }
+
+        fn parts(&self, value: &Value) -> Vec<Value> {
+            vec![value.downcast::<Twice>().inner.clone()]
+        }
+
+        fn evaluate(&self, _execution_context: &mut ExecutionContext, value: &Value) -> Result<Step, EvaluationError> {
+            Ok(Step::EvaluateThen(value.downcast::<Twice>().inner.clone(), Shared::new(Double)))
+        }
+
+        fn serialize(&self, value: &Value, f: &mut dyn FnMut(Value) -> JsonValue) -> serde_json::Map<String, JsonValue> {
+            let mut entry = serde_json::Map::new();
+            entry.insert("inner".to_owned(), f(value.downcast::<Twice>().inner.clone()));
+            entry
+        }
+
+        fn deserialize(&self, entry: &JsonValue, f: &mut dyn FnMut(JsonValue) -> Result<Value, SerializationError>) -> Result<Value, SerializationError> {
+            Ok(Value::new(Twice {
+                inner: f(entry.get("inner").ok_or(SerializationError::Malformed)?.clone())?,
+            }))
+        }
+
+        fn render(&self, _value: &Value) -> Rendering {
+            Rendering::text("twice")
+        }
+    }
+
+    struct Double;
+
+    impl Resume for Double {
+        fn resume(&self, _execution_context: &mut ExecutionContext, result: Value) -> Result<Step, EvaluationError> {
+            let result = number::expect_number(&result)?;
+            Ok(Step::ReturnSettled(number::add(result.clone(), result).into_value()))
+        }
+    }
+
+    struct Unregistered;
+
+    // A kind defined outside the crate is evaluated, compared and serialized like the builtin ones once it is registered.
+    #[test]
+    fn kinds_outside_the_machine() {
+        let twice = Value::new(Twice { inner: integer(1) });
+        assert!(kind_of(&twice).is_none());
+        register(Box::new(TwiceKind));
+        assert_eq!(kind_of(&twice).map(|kind| kind.name()), Some("Twice"));
+        assert!(kind_named("Twice").is_some());
+        let serialized = serialization::serialize(&mut SerializationStorage::new(), tuple(vec![twice.clone(), twice.clone()])).unwrap();
+        let deserialized = serialization::deserialize(&mut SerializationStorage::new(), &serialized).unwrap();
+        assert!(structural::structural_eq(&deserialized, &tuple(vec![twice.clone(), twice])));
+        let deserialized_parts = get_parts(deserialized);
+        assert!(deserialized_parts[0].ptr_eq(&deserialized_parts[1]));
+        let x = symbol("x");
+        let mut execution_context = ExecutionContext::new(HashMap::new());
+        execution_context.assign(x.clone(), integer(21));
+        let value = tuple(vec![Value::new(Twice { inner: dereference(&x) })]);
+        let result = evaluate(&mut execution_context, value.clone()).unwrap();
+        assert!(structural::structural_eq(&result, &tuple(vec![integer(42)])));
+        assert_eq!(get_parts(get_parts(value)[0].clone()).len(), 1);
+        let unregistered = Value::new(Unregistered);
+        assert!(get_parts(unregistered.clone()).is_empty());
+        assert!(structural::structural_eq(&unregistered, &unregistered));
+        let error = evaluate(&mut execution_context, tuple(vec![unregistered])).unwrap_err();
+        assert!(matches!(error.reason, EvaluationErrorReason::UnknownKind));
+    }
+}